@@ -40,6 +40,7 @@ struct FieldMeta {
     ty: syn::Type,
     is_data: bool,
     is_hidden: bool,
+    is_accessor: bool,
     serde_attrs: Vec<syn::Attribute>,
 }
 
@@ -48,6 +49,7 @@ impl FieldMeta {
         let mut serde_attrs = Vec::new();
         let mut is_data = false;
         let mut is_hidden = false;
+        let mut is_accessor = false;
         for attr in &field.attrs {
             if let Ok(meta) = attr.parse_meta() {
                 if let Some(ident) = meta.path().get_ident() {
@@ -61,6 +63,9 @@ impl FieldMeta {
                         "hidden" => {
                             is_hidden = true;
                         }
+                        "accessor" => {
+                            is_accessor = true;
+                        }
                         _ => {}
                     }
                 }
@@ -75,6 +80,7 @@ impl FieldMeta {
             ty: field.ty.clone(),
             is_data,
             is_hidden,
+            is_accessor,
             serde_attrs,
         }
     }
@@ -95,14 +101,23 @@ impl FieldMeta {
     }
 }
 
-struct PushField<'a>(&'a FieldMeta);
+/// Pushes a field as data property, from a copy if the instance is kept.
+struct PushField<'a>(&'a FieldMeta, bool);
 struct PeekField<'a>(&'a FieldMeta);
 
 impl<'a> quote::ToTokens for PushField<'a> {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         let name = &self.0.name;
         let prop_name = self.0.prop_name();
-        let q = if self.0.is_data {
+        let value = if self.1 {
+            quote!(self.#name.clone())
+        } else {
+            quote!(self.#name)
+        };
+        let q = if self.0.is_accessor {
+            // read through the accessors of the instance instead
+            quote!()
+        } else if self.0.is_data {
             let wrapper_name = Ident::new(
                 &format!("{}Wrapper", self.0.name.field_name().to_string()),
                 Span::call_site(),
@@ -124,13 +139,13 @@ impl<'a> quote::ToTokens for PushField<'a> {
                     }
                 }
 
-                #wrapper_name(self.#name).push_to(ctx);
+                #wrapper_name(#value).push_to(ctx);
                 ctx.put_prop_bytes(idx.try_into().unwrap(), #prop_name);
                 }
             }
         } else {
             quote! {
-                self.#name.push_to(ctx);
+                #value.push_to(ctx);
                 ctx.put_prop_bytes(idx.try_into().unwrap(), #prop_name);
             }
         };
@@ -173,7 +188,7 @@ impl<'a> quote::ToTokens for PeekField<'a> {
     }
 }
 
-#[proc_macro_derive(Value, attributes(duktape, data, hidden))]
+#[proc_macro_derive(Value, attributes(duktape, data, hidden, accessor))]
 pub fn value(input: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(input as syn::DeriveInput);
     let ident = input.ident.clone();
@@ -315,9 +330,42 @@ pub fn value(input: TokenStream) -> TokenStream {
         .iter()
         .map(|meta| meta.name.field_name().to_string())
         .collect();
-    let prop_names_str: Vec<_> = fields_meta.iter().map(|meta| meta.prop_name()).collect();
-    let fields_push: Vec<_> = fields_meta.iter().map(|meta| PushField(meta)).collect();
-    let fields_peek: Vec<_> = fields_meta.iter().map(|meta| PeekField(meta)).collect();
+    // objects keep the instance itself when accessors need to reach it
+    let owned = fields_meta.iter().any(|meta| meta.is_accessor);
+    let fields_push: Vec<_> = fields_meta
+        .iter()
+        .map(|meta| PushField(meta, owned))
+        .collect();
+    let fields_read: Vec<_> = fields_meta
+        .iter()
+        .zip(field_names_str.iter())
+        .map(|(meta, name_str)| {
+            let name = &meta.name;
+            let var = meta.name.field_name();
+            if meta.is_accessor {
+                quote! {
+                    let #var = match ctx.peek::<duktape::JsClass<Self>>(idx) {
+                        Ok(owned) => owned.borrow().#name.clone(),
+                        Err(_) => return Err(duktape::value::PeekError::Prop(#name_str)),
+                    };
+                }
+            } else {
+                let prop_name = meta.prop_name();
+                let peek = PeekField(meta);
+                quote! {
+                    if !ctx.get_prop_bytes(idx, #prop_name) {
+                        return Err(duktape::value::PeekError::Prop(#name_str));
+                    }
+                    let #var = #peek?;
+                }
+            }
+        })
+        .collect();
+    let attach = if owned {
+        quote!(duktape::JsClass::new(self).attach(ctx, idx.try_into().unwrap());)
+    } else {
+        quote!()
+    };
 
     let push = if flags & GENERATE_PUSH != 0 {
         quote! {
@@ -328,7 +376,10 @@ pub fn value(input: TokenStream) -> TokenStream {
                     #(
                         #fields_push
                     )*
-                    Self::register_methods(ctx, idx);
+                    // methods live on a prototype shared by all instances
+                    ctx.push_prototype::<Self>();
+                    ctx.set_prototype(idx.try_into().unwrap());
+                    #attach
                     idx
                 }
 
@@ -343,12 +394,7 @@ pub fn value(input: TokenStream) -> TokenStream {
             impl duktape::PeekValue for #ident {
                 fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, duktape::value::PeekError> {
                     ctx.get_object(idx);
-                    #(
-                        if !ctx.get_prop_bytes(idx, #prop_names_str) {
-                            return Err(duktape::value::PeekError::Prop(#field_names_str));
-                        }
-                        let #field_vars = #fields_peek?;
-                    )*
+                    #( #fields_read )*
                     Ok(Self {
                         #( #field_names: #field_vars ),*
                     })
@@ -366,6 +412,9 @@ pub fn value(input: TokenStream) -> TokenStream {
 struct Args {
     this: Option<Ident>,
     vararg: bool,
+    constructor: bool,
+    getter: Option<String>,
+    setter: Option<String>,
 }
 
 struct KV {
@@ -394,18 +443,32 @@ impl Parse for Args {
         let vars = syn::punctuated::Punctuated::<KV, syn::Token![,]>::parse_terminated(input)?;
         let mut this = None;
         let mut vararg = false;
+        let mut constructor = false;
+        let mut getter = None;
+        let mut setter = None;
         for var in vars {
             match var.name.to_string().as_str() {
                 "this" => this = Some(Ident::new(&var.value.unwrap(), Span::call_site())),
                 "vararg" => {
                     vararg = true;
                 }
+                "constructor" => {
+                    constructor = true;
+                }
+                "getter" => getter = Some(var.value.expect("getter needs a property name")),
+                "setter" => setter = Some(var.value.expect("setter needs a property name")),
                 attr => {
                     panic!("unknown attribute {}", attr);
                 }
             }
         }
-        Ok(Args { this, vararg })
+        Ok(Args {
+            this,
+            vararg,
+            constructor,
+            getter,
+            setter,
+        })
     }
 }
 
@@ -437,6 +500,8 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
         }
     };
     let mut is_method = false;
+    let mut mut_self = false;
+    let mut takes_ctx = false;
     for (i, param) in parsed.sig.inputs.iter().enumerate() {
         match param {
             syn::FnArg::Receiver(receiver) => {
@@ -444,6 +509,7 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
                     panic!("self not supported")
                 }
                 is_method = true;
+                mut_self = receiver.mutability.is_some();
                 continue;
             }
             syn::FnArg::Typed(pat_typ) => match &*pat_typ.ty {
@@ -454,6 +520,7 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
                     if i > 0 {
                         panic!("unsupported reference");
                     }
+                    takes_ctx = true;
                 }
                 _ => panic!("unsupported"),
             },
@@ -491,10 +558,11 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
     };
 
     let bare_func = {
-        let func_args_count = if parsed_attr.vararg {
+        // functions only taking the context read the whole stack
+        let func_args_count = if parsed_attr.vararg || args_count == 0 {
             -1
         } else {
-            raw_args_count
+            args_count
         };
         quote!(
             struct #struct_name;
@@ -502,33 +570,64 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
             impl duktape::Function for #struct_name {
                 const ARGS: i32 = #func_args_count;
 
-                fn ptr(&self) -> unsafe extern "C" fn(*mut duktape::sys::duk_context) -> i32 {
+                fn ptr(&self) -> unsafe extern "C-unwind" fn(*mut duktape::sys::duk_context) -> i32 {
                     Self::#fn_name
                 }
             }
 
             impl #struct_name {
-                pub unsafe extern "C" fn #fn_name(raw: *mut duktape::sys::duk_context) -> i32 {
+                pub unsafe extern "C-unwind" fn #fn_name(raw: *mut duktape::sys::duk_context) -> i32 {
                     #parsed
 
-                    // prevent drop
-                    let ctx = &mut std::mem::ManuallyDrop::new(duktape::Context::from_raw(raw));
-                    let n = ctx.stack_len();
-                    if n < #raw_args_count {
-                        return -1;
-                    }
-                    #(#args_getters)*
-                    if #raw_args_count > 0 {
-                        ctx.pop_n(#raw_args_count);
-                    }
-                    let result = #fn_name(ctx, #(#args_names),*);
-                    #push_result
-                    #return_count
+                    duktape::call_native(raw, |ctx: &mut duktape::Context| {
+                        let n = ctx.stack_len();
+                        if n < #args_count {
+                            return -1;
+                        }
+                        #(#args_getters)*
+                        if #args_count > 0 {
+                            ctx.pop_n(#args_count);
+                        }
+                        let result = #fn_name(ctx, #(#args_names),*);
+                        #push_result
+                        #return_count
+                    })
                 }
             }
         )
     };
-    let res = if !is_method {
+    let res = if parsed_attr.constructor {
+        if is_method {
+            panic!("constructor can't take self");
+        }
+        let ctx_arg = if takes_ctx { quote!(ctx,) } else { quote!() };
+        let args_getters = args.iter().zip(args_names.iter()).enumerate().map(|(i, (typ, name))| {
+            let name_str = name.to_string();
+            let arg_idx = i as i32;
+            quote!(
+                let #name = args.get::<#typ>(ctx, #arg_idx).expect(concat!("failed to peek ", #name_str));
+            )
+        });
+        quote!(
+            #parsed
+
+            /// Make the type constructible from scripts as `new name(...)`.
+            pub fn register_class(ctx: &mut duktape::Context, name: &str) {
+                ctx.push_closure(|ctx: &mut duktape::Context, args: duktape::Args| -> Self {
+                    if !ctx.is_constructor_call() {
+                        panic!("constructor requires 'new'");
+                    }
+                    #(#args_getters)*
+                    Self::#fn_name(#ctx_arg #(#args_names),*)
+                });
+                ctx.push_prototype::<Self>();
+                ctx.dup(-2);
+                ctx.put_prop_string(-2, "constructor");
+                ctx.put_prop_string(-2, "prototype");
+                ctx.put_global_string(name);
+            }
+        )
+    } else if !is_method {
         bare_func
     } else {
         let method_args_count = if parsed_attr.vararg {
@@ -541,6 +640,39 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
             Span::call_site(),
         );
         let outer_type = parsed_attr.this.unwrap();
+        // methods reach the instance kept by the object, `&self` methods
+        // fall back to a copy made from the object's properties
+        let resolve_this = if mut_self {
+            quote! {
+                let owned = ctx
+                    .peek::<duktape::JsClass<#outer_type>>(-1)
+                    .expect(concat!("this doesn't hold a ", stringify!(#outer_type)));
+                let mut this = owned.borrow_mut();
+            }
+        } else {
+            quote! {
+                let owned = ctx.peek::<duktape::JsClass<#outer_type>>(-1).ok();
+                let copy: #outer_type;
+                let guard;
+                let this: &#outer_type = match &owned {
+                    Some(owned) => {
+                        guard = owned.borrow();
+                        &*guard
+                    }
+                    None => {
+                        copy = ctx.peek(-1).expect("failed to peek this");
+                        &copy
+                    }
+                };
+            }
+        };
+        // accessors are installed under their property name, not `name`
+        let install = match (&parsed_attr.getter, &parsed_attr.setter) {
+            (Some(prop), None) => quote!(ctx.put_getter(idx.try_into().unwrap(), #prop);),
+            (None, Some(prop)) => quote!(ctx.put_setter(idx.try_into().unwrap(), #prop);),
+            (None, None) => quote!(ctx.put_prop_string(idx.try_into().unwrap(), name);),
+            (Some(_), Some(_)) => panic!("a method can't be both getter and setter"),
+        };
         quote!(
 
         #parsed
@@ -552,33 +684,33 @@ pub fn duktape(attr: TokenStream, input: TokenStream) -> TokenStream {
             impl duktape::Function for #struct_name {
                 const ARGS: i32 = #method_args_count;
 
-                fn ptr(&self) -> unsafe extern "C" fn(*mut ::duktape::sys::duk_context) -> i32 {
+                fn ptr(&self) -> unsafe extern "C-unwind" fn(*mut ::duktape::sys::duk_context) -> i32 {
                     Self::#fn_name
                 }
             }
 
             impl #struct_name {
-                pub unsafe extern "C" fn #fn_name(raw: *mut ::duktape::sys::duk_context) -> i32 {
-                    // prevent drop
-                    let ctx = &mut std::mem::ManuallyDrop::new(duktape::Context::from_raw(raw));
-                    let n = ctx.stack_len();
-                    if n < #method_args_count {
-                        return -1;
-                    }
-                    #(#args_getters)*
-                    ctx.push_this();
-                    let this: #outer_type = ctx.peek(-1).expect("failed to peek this");;
-                    if #method_args_count > 0 {
-                        ctx.pop_n(#method_args_count);
-                    }
-                    let result = this.#fn_name(#(#args_names),*);
-                    #push_result
-                    #return_count
+                pub unsafe extern "C-unwind" fn #fn_name(raw: *mut ::duktape::sys::duk_context) -> i32 {
+                    duktape::call_native(raw, |ctx: &mut duktape::Context| {
+                        let n = ctx.stack_len();
+                        if n < #method_args_count {
+                            return -1;
+                        }
+                        #(#args_getters)*
+                        ctx.push_this();
+                        #resolve_this
+                        if #method_args_count > 0 {
+                            ctx.pop_n(#method_args_count);
+                        }
+                        let result = this.#fn_name(#(#args_names),*);
+                        #push_result
+                        #return_count
+                    })
                 }
             }
             //println!("registering method `{}` of {} args", name, #method_args_count);
             ctx.push_function(#struct_name);
-            ctx.put_prop_string(idx.try_into().unwrap(), name);
+            #install
             }
         )
     };
@@ -227,3 +227,165 @@ fn adder() {
     let rv = ctx.peek::<u32>(-1).unwrap();
     assert_eq!(3, rv);
 }
+
+#[test]
+fn typed_args_from_js() {
+    #[duktape]
+    fn add(_ctx: &mut Context, a: u32, b: u32) -> u32 {
+        a + b
+    }
+
+    #[duktape]
+    fn double(_ctx: &mut Context, a: u32) -> u32 {
+        2 * a
+    }
+
+    let mut ctx = Context::default();
+    ctx.register_function("add", Add);
+    ctx.register_function("double", Double);
+    let n: u32 = ctx.eval("double(add(1, 2))").unwrap();
+    assert_eq!(n, 6);
+}
+
+#[test]
+fn panic_is_thrown_to_js() {
+    #[duktape]
+    fn check_positive(ctx: &mut Context) -> i32 {
+        let n = ctx.get_int(0);
+        if n <= 0 {
+            panic!("expected a positive number, got {}", n);
+        }
+        n
+    }
+
+    let mut ctx = Context::default();
+    ctx.register_function("checkPositive", CheckPositive);
+    let msg: String = ctx
+        .eval("try { checkPositive(-1) } catch (e) { e.message }")
+        .unwrap();
+    assert_eq!(msg, "expected a positive number, got -1");
+    ctx.pop().unwrap();
+
+    match ctx.eval::<i32>("checkPositive(0)") {
+        Err(duktape::Error::Js(e)) => {
+            assert_eq!(e.name.as_deref(), Some("Error"));
+            assert_eq!(e.message, "expected a positive number, got 0");
+            assert_eq!(e.to_string(), "Error: expected a positive number, got 0");
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    ctx.pop().unwrap();
+
+    let n: i32 = ctx.eval("checkPositive(2)").unwrap();
+    assert_eq!(n, 2);
+}
+
+#[test]
+fn shared_prototype() {
+    #[derive(Value)]
+    #[duktape(Peek, Push, Methods("norm"))]
+    pub struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    impl Point {
+        #[duktape(constructor)]
+        fn new(x: i32, y: i32) -> Self {
+            Point { x, y }
+        }
+
+        #[duktape(this = "Point")]
+        fn norm(&self) -> i32 {
+            self.x.abs() + self.y.abs()
+        }
+    }
+
+    let mut ctx = Context::default();
+    ctx.push(Point { x: 1, y: 2 });
+    ctx.put_global_string("a");
+    ctx.push(Point { x: 3, y: -4 });
+    ctx.put_global_string("b");
+    let shared: bool = ctx
+        .eval(
+            "Object.getPrototypeOf(a) === Object.getPrototypeOf(b) && \
+             !a.hasOwnProperty('norm') && a.norm() === 3 && b.norm() === 7",
+        )
+        .unwrap();
+    assert!(shared);
+    ctx.pop().unwrap();
+
+    Point::register_class(&mut ctx, "Point");
+    let p: Point = ctx.eval("var p = new Point(-5, 6); p.x = 7; p").unwrap();
+    assert_eq!((p.x, p.y), (7, 6));
+    ctx.pop().unwrap();
+    let is_point: bool = ctx
+        .eval("p instanceof Point && a instanceof Point && p.constructor === Point && p.norm() === 13")
+        .unwrap();
+    assert!(is_point);
+    ctx.pop().unwrap();
+    assert!(ctx.eval::<()>("Point(1, 2)").is_err());
+}
+
+#[test]
+fn accessors() {
+    #[derive(Value)]
+    #[duktape(Peek, Push, Methods("count", "setCount", "double", "describe"))]
+    pub struct Counter {
+        #[accessor]
+        count: u32,
+        label: String,
+    }
+
+    impl Counter {
+        #[duktape(this = "Counter", getter = "count")]
+        fn count(&self) -> u32 {
+            self.count
+        }
+
+        #[duktape(this = "Counter", setter = "count")]
+        fn set_count(&mut self, n: u32) {
+            self.count = n;
+        }
+
+        #[duktape(this = "Counter", getter = "double")]
+        fn double(&self) -> u32 {
+            self.count * 2
+        }
+
+        #[duktape(this = "Counter")]
+        fn describe(&self) -> String {
+            format!("{}: {}", self.label, self.count)
+        }
+    }
+
+    let mut ctx = Context::default();
+    ctx.push(Counter {
+        count: 1,
+        label: "hits".to_string(),
+    });
+    ctx.put_global_string("counter");
+
+    let double: u32 = ctx
+        .eval("counter.count = 5; counter.count += 1; counter.double")
+        .unwrap();
+    assert_eq!(double, 12);
+    ctx.pop().unwrap();
+
+    // the field is only reachable through the accessors
+    let own: bool = ctx.eval("counter.hasOwnProperty('count')").unwrap();
+    assert!(!own);
+    ctx.pop().unwrap();
+
+    // read-only without a setter
+    let res: u32 = ctx.eval("counter.double = 0; counter.double").unwrap();
+    assert_eq!(res, 12);
+    ctx.pop().unwrap();
+
+    let s: String = ctx.eval("counter.describe()").unwrap();
+    assert_eq!(s, "hits: 6");
+    ctx.pop().unwrap();
+
+    let counter: Counter = ctx.eval("counter").unwrap();
+    assert_eq!((counter.count, counter.label.as_str()), (6, "hits"));
+}
@@ -1,8 +1,12 @@
 extern crate bindgen;
 
 use std::env;
+use std::fs;
 use std::path::PathBuf;
 
+#[path = "src/fnv.rs"]
+mod fnv;
+
 fn main() {
     // Tell cargo to tell rustc to link the system bzip2
     // shared library.
@@ -11,6 +15,16 @@ fn main() {
     // Tell cargo to invalidate the built crate whenever the wrapper changes
     println!("cargo:rerun-if-changed=wrapper.h");
 
+    // Config options enabled by cargo features, see the override defines
+    // section of c/duk_config.h.
+    let mut defines = Vec::new();
+    if env::var_os("CARGO_FEATURE_EXEC_TIMEOUT").is_some() {
+        defines.push("DUK_RS_EXEC_TIMEOUT");
+    }
+    if env::var_os("CARGO_FEATURE_DEBUGGER").is_some() {
+        defines.push("DUK_RS_DEBUGGER");
+    }
+
     // The bindgen::Builder is the main entry point
     // to bindgen, and lets you build up options for
     // the resulting bindings.
@@ -19,6 +33,7 @@ fn main() {
         // bindings for.
         .header("wrapper.h")
         .clang_arg("-I./c")
+        .clang_args(defines.iter().map(|define| format!("-D{}", define)))
         .allowlist_var("DUK_(.*)")
         .allowlist_function("duk_(.*)")
         // Tell cargo to invalidate the built crate whenever any of the
@@ -29,14 +44,59 @@ fn main() {
         // Unwrap the Result and panic on failure.
         .expect("Unable to generate bindings");
 
+    // Fatal errors are reported by unwinding out of the fatal handler
+    // through the engine, so every entry point and callback has to allow
+    // unwinding.
+    let bindings = bindings
+        .to_string()
+        .replace("extern \"C\"", "extern \"C-unwind\"");
+
     // Write the bindings to the $OUT_DIR/bindings.rs file.
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-    bindings
-        .write_to_file(out_path.join("bindings.rs"))
-        .expect("Couldn't write bindings!");
+    fs::write(out_path.join("bindings.rs"), bindings).expect("Couldn't write bindings!");
+
+    // Bytecode is only portable between identical builds of the engine,
+    // fingerprint the sources, config and target it is compiled for.
+    let mut build_id = fnv::OFFSET;
+    for file in [
+        "c/duktape.h",
+        "c/duk_config.h",
+        "c/duktape.c",
+        "c/duk_rs.h",
+        "c/duk_rs.c",
+    ] {
+        println!("cargo:rerun-if-changed={}", file);
+        build_id = fnv::fnv1a(
+            build_id,
+            &fs::read(file).expect("Couldn't read engine sources"),
+        );
+    }
+    for define in &defines {
+        build_id = fnv::fnv1a(build_id, define.as_bytes());
+    }
+    for var in ["CARGO_CFG_TARGET_ENDIAN", "CARGO_CFG_TARGET_POINTER_WIDTH"] {
+        build_id = fnv::fnv1a(build_id, env::var(var).unwrap_or_default().as_bytes());
+    }
+    fs::write(
+        out_path.join("build_id.rs"),
+        format!(
+            "/// Fingerprint of the engine sources and configuration, dumped\n\
+             /// bytecode can only be loaded by the same build.\n\
+             pub const DUK_RS_BUILD_ID: u64 = {:#018x};\n",
+            build_id
+        ),
+    )
+    .expect("Couldn't write build id!");
 
-    cc::Build::new()
-        .file("c/duktape.c")
+    let mut build = cc::Build::new();
+    for define in &defines {
+        build.define(define, None);
+    }
+    // duk_rs.c includes the engine itself
+    build
+        .file("c/duk_rs.c")
         .include("c/")
+        // unwind tables for the C frames a fatal error unwinds through
+        .flag_if_supported("-fexceptions")
         .compile("duktape");
 }
@@ -3097,6 +3097,18 @@ typedef struct duk_hthread duk_context;
 
 /* __OVERRIDE_DEFINES__ */
 
+/* Options switched on by duktape-sys cargo features, see build.rs. */
+#if defined(DUK_RS_EXEC_TIMEOUT)
+#define DUK_USE_INTERRUPT_COUNTER
+#define DUK_USE_EXEC_TIMEOUT_CHECK(udata) duk_rs_exec_timeout_check((udata))
+/* Provided by the duktape crate, receives the heap udata. */
+extern duk_bool_t duk_rs_exec_timeout_check(void *udata);
+#endif
+#if defined(DUK_RS_DEBUGGER)
+#define DUK_USE_INTERRUPT_COUNTER
+#define DUK_USE_DEBUGGER_SUPPORT
+#endif
+
 /*
  *  Conditional includes
  */
@@ -0,0 +1,48 @@
+/*
+ *  Additions to the duktape API which need engine internals.  The engine
+ *  is compiled as part of this file so they are visible here.
+ */
+
+#include "duktape.c"
+#include "duk_rs.h"
+
+DUK_LOCAL void duk__rs_count_list(duk_heap *heap, duk_heaphdr *list, duk_size_t *objects, duk_size_t *buffers) {
+	duk_heaphdr *curr;
+
+	DUK_UNREF(heap);  /* only used with pointer compression */
+
+	for (curr = list; curr != NULL; curr = DUK_HEAPHDR_GET_NEXT(heap, curr)) {
+		switch (DUK_HEAPHDR_GET_TYPE(curr)) {
+		case DUK_HTYPE_OBJECT:
+			(*objects)++;
+			break;
+		case DUK_HTYPE_BUFFER:
+			(*buffers)++;
+			break;
+		default:
+			break;
+		}
+	}
+}
+
+DUK_EXTERNAL void duk_rs_get_heap_counts(duk_context *ctx, duk_size_t *objects, duk_size_t *strings, duk_size_t *buffers) {
+	duk_heap *heap;
+
+	DUK_ASSERT_API_ENTRY(ctx);
+
+	heap = ctx->heap;
+	*objects = 0;
+	*buffers = 0;
+
+	/* Strings live in the string table, everything else in the
+	 * allocated list or waiting for its finalizer.
+	 */
+	duk__rs_count_list(heap, heap->heap_allocated, objects, buffers);
+#if defined(DUK_USE_FINALIZER_SUPPORT)
+	duk__rs_count_list(heap, heap->finalize_list, objects, buffers);
+#endif
+#if defined(DUK_USE_REFERENCE_COUNTING)
+	duk__rs_count_list(heap, heap->refzero_list, objects, buffers);
+#endif
+	*strings = (duk_size_t) heap->st_count;
+}
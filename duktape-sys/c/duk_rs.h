@@ -0,0 +1,13 @@
+/*
+ *  Additions to the duktape API made by duktape-sys, see duk_rs.c.
+ */
+
+#if !defined(DUK_RS_H_INCLUDED)
+#define DUK_RS_H_INCLUDED
+
+#include "duktape.h"
+
+/* Number of values of each heap allocated type in a heap. */
+DUK_EXTERNAL_DECL void duk_rs_get_heap_counts(duk_context *ctx, duk_size_t *objects, duk_size_t *strings, duk_size_t *buffers);
+
+#endif  /* DUK_RS_H_INCLUDED */
@@ -0,0 +1,11 @@
+//! 64 bit FNV-1a, shared by the build script fingerprinting the engine and
+//! the checksum of dumped bytecode.
+
+pub const OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Continue `hash` with `bytes`, a new hash starts at [`OFFSET`].
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
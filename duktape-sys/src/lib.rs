@@ -3,6 +3,10 @@
 #![allow(non_snake_case)]
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+include!(concat!(env!("OUT_DIR"), "/build_id.rs"));
+
+#[doc(hidden)]
+pub mod fnv;
 
 #[cfg(test)]
 mod tests {
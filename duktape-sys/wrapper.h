@@ -1,2 +1,3 @@
 #include <duk_config.h>
 #include <duktape.h>
+#include <duk_rs.h>
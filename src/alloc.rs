@@ -0,0 +1,156 @@
+//! Rust side allocator installed into every duktape heap.
+//!
+//! Each allocation is prefixed with a small header holding its size, so
+//! the heap can keep an exact count of live bytes and refuse allocations
+//! that would go over the configured limit. Duktape reacts to a refused
+//! allocation by running the garbage collector and, if that does not help,
+//! by throwing an error which is reported as [`Error::OutOfMemory`](crate::Error::OutOfMemory).
+
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::ffi::c_void;
+
+use crate::heap::HeapState;
+
+// large enough to keep the returned pointer aligned for any duktape value
+const HEADER: usize = 16;
+const ALIGN: usize = 16;
+
+pub(crate) struct Memory {
+    limit: Option<usize>,
+    allocated: Cell<usize>,
+    peak: Cell<usize>,
+    blocks: Cell<usize>,
+    exhausted: Cell<bool>,
+}
+
+impl Memory {
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        Memory {
+            limit,
+            allocated: Cell::new(0),
+            peak: Cell::new(0),
+            blocks: Cell::new(0),
+            exhausted: Cell::new(false),
+        }
+    }
+
+    /// Bytes currently allocated by the heap.
+    pub(crate) fn allocated(&self) -> usize {
+        self.allocated.get()
+    }
+
+    /// Most bytes allocated at once so far.
+    pub(crate) fn peak(&self) -> usize {
+        self.peak.get()
+    }
+
+    /// Number of live allocations.
+    pub(crate) fn blocks(&self) -> usize {
+        self.blocks.get()
+    }
+
+    /// Returns whether an allocation was refused since the last call.
+    pub(crate) fn take_exhausted(&self) -> bool {
+        self.exhausted.replace(false)
+    }
+
+    fn reserve(&self, old: usize, new: usize) -> bool {
+        let next = self.allocated.get() - old + new;
+        if let Some(limit) = self.limit {
+            if next > limit {
+                self.exhausted.set(true);
+                return false;
+            }
+        }
+        self.allocated.set(next);
+        true
+    }
+
+    // Only called once an allocation succeeded, refused or failed ones
+    // never count towards the peak.
+    fn update_peak(&self) {
+        self.peak.set(self.peak.get().max(self.allocated.get()));
+    }
+
+    fn release(&self, size: usize) {
+        self.allocated.set(self.allocated.get() - size);
+    }
+
+    unsafe fn alloc(&self, size: usize) -> *mut c_void {
+        if !self.reserve(0, size) {
+            return std::ptr::null_mut();
+        }
+        let layout = Layout::from_size_align_unchecked(size + HEADER, ALIGN);
+        let base = std::alloc::alloc(layout);
+        if base.is_null() {
+            self.release(size);
+            return std::ptr::null_mut();
+        }
+        (base as *mut usize).write(size);
+        self.blocks.set(self.blocks.get() + 1);
+        self.update_peak();
+        base.add(HEADER) as *mut c_void
+    }
+
+    unsafe fn realloc(&self, ptr: *mut c_void, size: usize) -> *mut c_void {
+        if ptr.is_null() {
+            return self.alloc(size);
+        }
+        if size == 0 {
+            self.free(ptr);
+            return std::ptr::null_mut();
+        }
+        let base = (ptr as *mut u8).sub(HEADER);
+        let old = (base as *const usize).read();
+        if !self.reserve(old, size) {
+            return std::ptr::null_mut();
+        }
+        let layout = Layout::from_size_align_unchecked(old + HEADER, ALIGN);
+        let base = std::alloc::realloc(base, layout, size + HEADER);
+        if base.is_null() {
+            // the old block is still alive, undo the reservation
+            self.allocated.set(self.allocated.get() - size + old);
+            return std::ptr::null_mut();
+        }
+        (base as *mut usize).write(size);
+        self.update_peak();
+        base.add(HEADER) as *mut c_void
+    }
+
+    unsafe fn free(&self, ptr: *mut c_void) {
+        if ptr.is_null() {
+            return;
+        }
+        let base = (ptr as *mut u8).sub(HEADER);
+        let size = (base as *const usize).read();
+        std::alloc::dealloc(
+            base,
+            Layout::from_size_align_unchecked(size + HEADER, ALIGN),
+        );
+        self.release(size);
+        self.blocks.set(self.blocks.get() - 1);
+    }
+}
+
+pub(crate) unsafe extern "C-unwind" fn alloc(
+    udata: *mut c_void,
+    size: duktape_sys::duk_size_t,
+) -> *mut c_void {
+    let state = &*(udata as *const HeapState);
+    state.memory.alloc(size as usize)
+}
+
+pub(crate) unsafe extern "C-unwind" fn realloc(
+    udata: *mut c_void,
+    ptr: *mut c_void,
+    size: duktape_sys::duk_size_t,
+) -> *mut c_void {
+    let state = &*(udata as *const HeapState);
+    state.memory.realloc(ptr, size as usize)
+}
+
+pub(crate) unsafe extern "C-unwind" fn free(udata: *mut c_void, ptr: *mut c_void) {
+    let state = &*(udata as *const HeapState);
+    state.memory.free(ptr)
+}
@@ -0,0 +1,68 @@
+use crate::alloc::{self, Memory};
+use crate::fatal::{self, FatalHandler};
+use crate::heap::HeapState;
+use crate::{Context, Error};
+
+/// Configures and creates a [`Context`].
+///
+/// ```
+///     use duktape::{ContextBuilder, Error};
+///
+///     let mut ctx = ContextBuilder::new()
+///         .memory_limit(1024 * 1024)
+///         .build()
+///         .unwrap();
+///     let res = ctx.eval::<()>("var a = []; while (true) { a.push('x' + a.length) }");
+///     assert!(matches!(res, Err(Error::OutOfMemory)));
+/// ```
+#[derive(Default)]
+pub struct ContextBuilder {
+    memory_limit: Option<usize>,
+    on_fatal: Option<FatalHandler>,
+}
+
+impl ContextBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of bytes the heap may allocate.
+    ///
+    /// Once the limit is reached allocations fail, which scripts observe as
+    /// a thrown error and the Rust caller as [`Error::OutOfMemory`].
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Get notified about fatal errors.
+    ///
+    /// A fatal error poisons the context: the call that ran into it returns
+    /// [`Error::Fatal`] and every following call [`Error::Poisoned`].
+    pub fn fatal_handler<F: Fn(&str) + 'static>(mut self, handler: F) -> Self {
+        self.on_fatal = Some(Box::new(handler));
+        self
+    }
+
+    pub fn build(self) -> Result<Context, Error> {
+        let state = Box::into_raw(Box::new(HeapState::new(
+            Memory::new(self.memory_limit),
+            self.on_fatal,
+        )));
+        let inner = unsafe {
+            duktape_sys::duk_create_heap(
+                Some(alloc::alloc),
+                Some(alloc::realloc),
+                Some(alloc::free),
+                state as *mut _,
+                Some(fatal::fatal),
+            )
+        };
+        if inner.is_null() {
+            drop(unsafe { Box::from_raw(state) });
+            return Err(Error::OutOfMemory);
+        }
+        unsafe { (*state).link.set(inner) };
+        Ok(Context { inner })
+    }
+}
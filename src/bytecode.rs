@@ -0,0 +1,152 @@
+//! Dumping compiled scripts to bytecode and loading them back.
+//!
+//! Duktape doesn't validate bytecode and loading anything but the output
+//! of the very same engine build is memory unsafe. Dumps are therefore
+//! wrapped in a header carrying the build fingerprint and a checksum,
+//! which [`Context::load_bytecode`] verifies before handing the bytecode
+//! to the engine.
+
+use crate::value::PushValue;
+use crate::{Context, Error, JsFunction, Script};
+
+const MAGIC: &[u8; 4] = b"DKBC";
+const HEADER_LEN: usize = 4 + 8 + 4 + 8;
+
+impl Script {
+    /// Dump the compiled function, to be loaded later with
+    /// [`Context::load_bytecode`] by the same build of the engine.
+    pub fn dump(&self, ctx: &mut Context) -> Result<Vec<u8>, Error> {
+        dump(ctx, self)
+    }
+}
+
+impl JsFunction {
+    /// Dump a script function like [`Script::dump`], native and bound
+    /// functions can't be dumped.
+    pub fn dump(&self, ctx: &mut Context) -> Result<Vec<u8>, Error> {
+        dump(ctx, self)
+    }
+}
+
+fn dump(ctx: &mut Context, function: impl PushValue) -> Result<Vec<u8>, Error> {
+    // dumping anything but a script function throws
+    let code = ctx.safe_call(0, |ctx| {
+        ctx.push(function);
+        unsafe { duktape_sys::duk_dump_function(ctx.inner) };
+        ctx.get_buffer(-1)
+    })?;
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + code.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&duktape_sys::DUK_RS_BUILD_ID.to_le_bytes());
+    bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&code).to_le_bytes());
+    bytes.extend_from_slice(&code);
+    Ok(bytes)
+}
+
+impl Context {
+    /// Load bytecode produced by [`Script::dump`].
+    ///
+    /// Returns [`Error::InvalidBytecode`] if it was dumped by a different
+    /// build of the engine or got corrupted.
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<Script, Error> {
+        let code = verify(bytes).ok_or(Error::InvalidBytecode)?;
+        self.protect(|ctx| {
+            ctx.push_fixed_buffer(code);
+            unsafe { duktape_sys::duk_load_function(ctx.inner) };
+            Ok(Script::from_top(ctx))
+        })
+    }
+}
+
+fn verify(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return None;
+    }
+    let build_id = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
+    let len = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    let sum = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+    let code = &bytes[HEADER_LEN..];
+    if build_id != duktape_sys::DUK_RS_BUILD_ID || len as usize != code.len() {
+        return None;
+    }
+    if checksum(code) != sum {
+        return None;
+    }
+    Some(code)
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    duktape_sys::fnv::fnv1a(duktape_sys::fnv::OFFSET, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompileOptions;
+
+    #[test]
+    fn dump_and_load() {
+        let mut ctx = Context::default();
+        let options = CompileOptions {
+            filename: Some("rule.js".to_string()),
+            function_mode: true,
+            ..Default::default()
+        };
+        let script = ctx
+            .compile("function (x) { return x * 2 }", options)
+            .unwrap();
+        let bytes = script.dump(&mut ctx).unwrap();
+        assert_eq!(ctx.stack_len(), 0);
+
+        let mut other = Context::default();
+        let loaded = other.load_bytecode(&bytes).unwrap();
+        other.push(&loaded);
+        other.push_int(21);
+        other.call(1).unwrap();
+        assert_eq!(other.get_int(-1), 42);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let mut ctx = Context::default();
+        let script = ctx.compile("1 + 1", CompileOptions::default()).unwrap();
+        let bytes = script.dump(&mut ctx).unwrap();
+
+        let mut foreign = bytes.clone();
+        foreign[4] ^= 1;
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let truncated = &bytes[..bytes.len() - 1];
+        for bytes in [&foreign[..], &corrupted, truncated, &[0xbf]] {
+            assert!(matches!(
+                ctx.load_bytecode(bytes),
+                Err(Error::InvalidBytecode)
+            ));
+        }
+
+        let loaded = ctx.load_bytecode(&bytes).unwrap();
+        let x: u32 = loaded.run(&mut ctx).unwrap();
+        assert_eq!(x, 2);
+    }
+
+    #[test]
+    fn dump_function() {
+        let mut ctx = Context::default();
+        let double: JsFunction = ctx.eval("(function (x) { return x * 2 })").unwrap();
+        ctx.pop_it();
+        let bytes = double.dump(&mut ctx).unwrap();
+        let loaded = ctx.load_bytecode(&bytes).unwrap();
+        ctx.push(&loaded);
+        ctx.push_int(21);
+        ctx.call(1).unwrap();
+        assert_eq!(ctx.get_int(-1), 42);
+        ctx.pop_it();
+
+        let native: JsFunction = ctx.eval("Math.max").unwrap();
+        ctx.pop_it();
+        assert!(matches!(native.dump(&mut ctx), Err(Error::Js(_))));
+        assert_eq!(ctx.stack_len(), 0);
+    }
+}
@@ -0,0 +1,335 @@
+//! Rust values owned by JS objects.
+//!
+//! The value is boxed and stored as a pointer in a hidden property of a
+//! plain object, whose finalizer drops it once the object is garbage
+//! collected, or at the latest when the heap is destroyed.
+//!
+//! Both the property and the finalizer are inherited by objects created
+//! from the object, so the box remembers the object owning it and is only
+//! used and dropped through that object.
+
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::rc::Rc;
+
+use crate::native::call_native;
+use crate::value::{PeekError, PeekValue, PushValue};
+use crate::Context;
+
+// hidden symbols start with 0xff and can't be reached from scripts
+const VALUE_PROP: &[u8] = b"\xffvalue";
+
+type Owned = Box<dyn Any>;
+
+struct Holder {
+    // heap pointer of the object owning the value
+    owner: *mut std::ffi::c_void,
+    value: Owned,
+}
+
+/// Push an object owning `value`.
+pub(crate) fn push_owned(ctx: &mut Context, value: Owned) -> u32 {
+    let idx = ctx.push_object();
+    attach_owned(ctx, idx as i32, value);
+    idx
+}
+
+/// Make the object at `idx`, which doesn't own a value yet, own `value`.
+fn attach_owned(ctx: &mut Context, idx: i32, value: Owned) {
+    let idx = unsafe { duktape_sys::duk_normalize_index(ctx.inner, idx) };
+    assert!(
+        owned_ptr(ctx, idx).is_none(),
+        "object already owns a Rust value"
+    );
+    ctx.heap().add_local_values(1);
+    let ptr = Box::into_raw(Box::new(Holder {
+        owner: unsafe { duktape_sys::duk_get_heapptr(ctx.inner, idx) },
+        value,
+    }));
+    ctx.push_pointer(ptr as *const _);
+    ctx.put_prop_bytes(idx, VALUE_PROP);
+    unsafe {
+        duktape_sys::duk_push_c_function(ctx.inner, Some(finalize_owned), 1);
+        duktape_sys::duk_set_finalizer(ctx.inner, idx);
+    }
+}
+
+/// Get a `T` owned by the object at `idx` out of it.
+pub(crate) fn peek_owned<T: Clone + 'static>(ctx: &mut Context, idx: i32) -> Option<T> {
+    let ptr = owned_ptr(ctx, idx)?;
+    let owned = unsafe { &(*ptr).value };
+    owned.downcast_ref::<T>().cloned()
+}
+
+// The value owned by the object at `idx`, `None` for objects which only
+// inherit one.
+fn owned_ptr(ctx: &mut Context, idx: i32) -> Option<*mut Holder> {
+    if unsafe { duktape_sys::duk_is_object(ctx.inner, idx) } == 0 {
+        return None;
+    }
+    ctx.get_prop_bytes(idx, VALUE_PROP);
+    let ptr = unsafe { duktape_sys::duk_get_pointer(ctx.inner, -1) } as *mut Holder;
+    ctx.pop_it();
+    let holder = unsafe { duktape_sys::duk_get_heapptr(ctx.inner, idx) };
+    if ptr.is_null() || unsafe { (*ptr).owner } != holder {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+unsafe extern "C-unwind" fn finalize_owned(raw: *mut duktape_sys::duk_context) -> i32 {
+    call_native(raw, |ctx| {
+        if let Some(ptr) = owned_ptr(ctx, 0) {
+            // a finalizer may run again if the object was rescued
+            ctx.push_pointer(std::ptr::null());
+            ctx.put_prop_bytes(0, VALUE_PROP);
+            ctx.heap().add_local_values(-1);
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+        0
+    })
+}
+
+impl Context {
+    /// Push the prototype shared by all objects pushed for `T`.
+    ///
+    /// It is created on first use for each heap, with the methods installed
+    /// by [`PushValue::register_methods`].
+    pub fn push_prototype<T: PushValue + 'static>(&mut self) -> u32 {
+        let type_id = TypeId::of::<T>();
+        let slot = self.heap().prototypes.borrow().get(&type_id).copied();
+        unsafe { duktape_sys::duk_push_heap_stash(self.inner) };
+        match slot {
+            Some(slot) => {
+                self.get_prop_index(slot, -1);
+            }
+            None => {
+                let idx = self.push_object();
+                T::register_methods(self, idx);
+                let slot = self.heap().new_slot();
+                self.dup(-1);
+                unsafe { duktape_sys::duk_put_prop_index(self.inner, -3, slot) };
+                self.heap().prototypes.borrow_mut().insert(type_id, slot);
+            }
+        }
+        unsafe { duktape_sys::duk_remove(self.inner, -2) };
+        self.stack_top()
+    }
+}
+
+/// A Rust value shared between Rust and the JS objects it is pushed as.
+///
+/// Each push creates a new object holding a reference to the value, which
+/// is dropped together with the last handle or object. Native methods get
+/// hold of the value by peeking `this`:
+///
+/// ```
+///     use duktape::{Context, JsClass};
+///
+///     struct Counter {
+///         n: u32,
+///     }
+///
+///     let mut ctx = Context::default();
+///     let counter = JsClass::new(Counter { n: 0 });
+///     ctx.push(counter.clone());
+///     ctx.push_closure(|ctx, _args| {
+///         ctx.push_this();
+///         let this: JsClass<Counter> = ctx.pop_value().unwrap();
+///         let mut counter = this.borrow_mut();
+///         counter.n += 1;
+///         counter.n
+///     });
+///     ctx.put_prop_string(0, "increment");
+///     ctx.put_global_string("counter");
+///
+///     let n: u32 = ctx.eval("counter.increment(); counter.increment()").unwrap();
+///     assert_eq!(n, 2);
+///     assert_eq!(counter.borrow().n, 2);
+/// ```
+pub struct JsClass<T> {
+    value: Rc<RefCell<T>>,
+}
+
+impl<T> JsClass<T> {
+    pub fn new(value: T) -> Self {
+        JsClass {
+            value: Rc::new(RefCell::new(value)),
+        }
+    }
+
+    /// Borrow the value, panics if it's mutably borrowed.
+    ///
+    /// A panic in a native function is thrown to the calling script.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.value.borrow()
+    }
+
+    /// Mutably borrow the value, panics if it's borrowed.
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.value.borrow_mut()
+    }
+}
+
+impl<T: 'static> JsClass<T> {
+    /// Make the object at `idx` hold the value, like the objects pushed
+    /// for it. This is how types deriving `Value` with `#[accessor]` fields
+    /// keep their instance with the object.
+    ///
+    /// Panics if the object already holds a Rust value.
+    pub fn attach(self, ctx: &mut Context, idx: i32) {
+        attach_owned(ctx, idx, Box::new(self.value));
+    }
+}
+
+impl<T> Clone for JsClass<T> {
+    fn clone(&self) -> Self {
+        JsClass {
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<T: 'static> PushValue for JsClass<T> {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        self.value.push_to(ctx)
+    }
+}
+
+impl<T: 'static> PeekValue for JsClass<T> {
+    fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
+        let value = Rc::<RefCell<T>>::peek_at(ctx, idx)?;
+        Ok(JsClass { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    struct Point {
+        x: i32,
+    }
+
+    #[test]
+    fn dropped_when_collected() {
+        let mut ctx = Context::default();
+        let point = JsClass::new(Point { x: 1 });
+        ctx.push(point.clone());
+        ctx.put_global_string("point");
+        assert_eq!(Rc::strong_count(&point.value), 2);
+
+        let same: JsClass<Point> = ctx.eval("point").unwrap();
+        ctx.pop_it();
+        same.borrow_mut().x = 2;
+        drop(same);
+        assert_eq!(point.borrow().x, 2);
+
+        ctx.eval::<()>("point = null").unwrap();
+        ctx.pop_it();
+        unsafe { duktape_sys::duk_gc(ctx.inner, 0) };
+        assert_eq!(Rc::strong_count(&point.value), 1);
+    }
+
+    #[test]
+    fn conflicting_borrow_is_thrown() {
+        let mut ctx = Context::default();
+        let point = JsClass::new(Point { x: 1 });
+        ctx.push(point.clone());
+        ctx.push_closure(|ctx, _args| {
+            ctx.push_this();
+            let this: JsClass<Point> = ctx.pop_value().unwrap();
+            this.borrow_mut().x += 1;
+        });
+        ctx.put_prop_string(0, "move");
+        ctx.put_global_string("point");
+
+        let guard = point.borrow();
+        assert!(matches!(ctx.eval::<()>("point.move()"), Err(Error::Js(_))));
+        ctx.pop_it();
+        drop(guard);
+        ctx.eval::<()>("point.move()").unwrap();
+        assert_eq!(point.borrow().x, 2);
+    }
+
+    #[test]
+    fn inherited_by_other_objects() {
+        let mut ctx = Context::default();
+        let point = JsClass::new(Point { x: 1 });
+        ctx.push(point.clone());
+        ctx.push_closure(|ctx, _args| {
+            ctx.push_this();
+            let this: JsClass<Point> = ctx.pop_value().unwrap();
+            this.borrow_mut().x += 1;
+        });
+        ctx.put_prop_string(0, "move");
+        ctx.put_global_string("point");
+
+        // objects inheriting the value and its finalizer don't own it
+        ctx.eval::<()>(
+            "var o = Object.create(point);
+            Duktape.fin(point)(Object.create(point));
+            o = null;
+            Duktape.gc();
+            point.move()",
+        )
+        .unwrap();
+        ctx.pop_it();
+        assert_eq!(point.borrow().x, 2);
+        assert_eq!(Rc::strong_count(&point.value), 2);
+        assert!(ctx.eval::<()>("Object.create(point).move()").is_err());
+        ctx.pop_it();
+        assert_eq!(point.borrow().x, 2);
+    }
+
+    #[test]
+    fn prototype_per_type() {
+        struct A;
+        struct B;
+
+        impl PushValue for A {
+            fn push_to(self, ctx: &mut Context) -> u32 {
+                ctx.push_object()
+            }
+
+            fn register_methods(ctx: &mut Context, idx: u32) {
+                ctx.push_string("a");
+                ctx.put_prop_string(idx as i32, "name");
+            }
+        }
+
+        impl PushValue for B {
+            fn push_to(self, ctx: &mut Context) -> u32 {
+                ctx.push_object()
+            }
+
+            fn register_methods(ctx: &mut Context, idx: u32) {
+                ctx.push_string("b");
+                ctx.put_prop_string(idx as i32, "name");
+            }
+        }
+
+        let mut ctx = Context::default();
+        ctx.push_prototype::<A>();
+        ctx.push_prototype::<B>();
+        ctx.push_prototype::<A>();
+        assert!(unsafe { duktape_sys::duk_strict_equals(ctx.inner, 0, 2) } != 0);
+        ctx.get_prop(1, "name");
+        assert_eq!(ctx.pop_value::<String>().unwrap(), "b");
+        ctx.get_prop(2, "name");
+        assert_eq!(ctx.pop_value::<String>().unwrap(), "a");
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut ctx = Context::default();
+        ctx.push(JsClass::new(Point { x: 1 }));
+        assert!(ctx.peek::<JsClass<String>>(-1).is_err());
+        ctx.push_object();
+        assert!(ctx.peek::<JsClass<Point>>(-1).is_err());
+        ctx.push_int(1);
+        assert!(ctx.peek::<JsClass<Point>>(-1).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+//! Rust closures callable from JS.
+//!
+//! The boxed closure is stored as a pointer in a hidden property of its
+//! function object and dropped by a finalizer once the function is
+//! garbage collected, or at the latest when the heap is destroyed.
+//!
+//! Both the property and the finalizer are inherited by objects created
+//! from the function, so the box remembers the function owning it and is
+//! only used and dropped through that function.
+
+use std::cell::Cell;
+
+use crate::native::{call_native, call_throwing, Throw};
+use crate::value::{PeekError, PeekValue, PushValue};
+use crate::Context;
+
+// hidden symbols start with 0xff and can't be reached from scripts
+const CLOSURE_PROP: &[u8] = b"\xffclosure";
+
+/// Arguments a closure was called with.
+#[derive(Debug, Clone, Copy)]
+pub struct Args {
+    len: i32,
+}
+
+impl Args {
+    pub fn len(&self) -> i32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Peek argument `n`, missing arguments are `undefined`.
+    pub fn get<T: PeekValue>(&self, ctx: &mut Context, n: i32) -> Result<T, PeekError> {
+        if n >= self.len {
+            ctx.push_undefined();
+        } else {
+            ctx.dup(n);
+        }
+        ctx.peek_protected()
+            .map_err(|err| PeekError::Thrown(Box::new(err)))?
+    }
+}
+
+/// Empty while the closure runs.
+type Closure<F> = Cell<Option<F>>;
+
+struct Stored<F> {
+    // heap pointer of the function the closure belongs to
+    owner: *mut std::ffi::c_void,
+    closure: Closure<F>,
+}
+
+// Puts the closure back once it returned or panicked.
+struct Running<'a, F> {
+    closure: &'a Closure<F>,
+    f: Option<F>,
+}
+
+impl<F> Drop for Running<'_, F> {
+    fn drop(&mut self) {
+        self.closure.set(self.f.take());
+    }
+}
+
+impl Context {
+    /// Push a function calling `f`.
+    ///
+    /// Panics in `f` are thrown to the calling script as an `Error`, as are
+    /// recursive calls of `f` from scripts it runs.
+    pub fn push_closure<F, R>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Context, Args) -> R + 'static,
+        R: PushValue,
+    {
+        self.push_closure_impl::<_, R, true>(move |ctx, args| Ok(f(ctx, args)))
+    }
+
+    /// Push a function calling `f`, which may be sent to another thread
+    /// together with the heap.
+    pub(crate) fn push_send_closure<F, R>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Context, Args) -> R + Send + 'static,
+        R: PushValue,
+    {
+        self.push_throwing_closure(move |ctx, args| Ok(f(ctx, args)))
+    }
+
+    /// Push a `Send` function calling `f` and throwing the errors it returns.
+    pub(crate) fn push_throwing_closure<F, R>(&mut self, f: F)
+    where
+        F: FnMut(&mut Context, Args) -> Result<R, Throw> + Send + 'static,
+        R: PushValue,
+    {
+        self.push_closure_impl::<F, R, false>(f)
+    }
+
+    // `LOCAL` closures aren't `Send` and are counted by the heap state.
+    fn push_closure_impl<F, R, const LOCAL: bool>(&mut self, f: F)
+    where
+        F: FnMut(&mut Context, Args) -> Result<R, Throw> + 'static,
+        R: PushValue,
+    {
+        if LOCAL {
+            self.heap().add_local_values(1);
+        }
+        unsafe {
+            duktape_sys::duk_push_c_function(
+                self.inner,
+                Some(call_closure::<F, R>),
+                duktape_sys::DUK_VARARGS,
+            );
+            let closure = Box::into_raw(Box::new(Stored {
+                owner: duktape_sys::duk_get_heapptr(self.inner, -1),
+                closure: Cell::new(Some(f)),
+            }));
+            self.push_pointer(closure as *const _);
+            self.put_prop_bytes(-2, CLOSURE_PROP);
+            duktape_sys::duk_push_c_function(self.inner, Some(finalize_closure::<F, LOCAL>), 1);
+            duktape_sys::duk_set_finalizer(self.inner, -2);
+        }
+    }
+
+    /// Make `f` available to scripts as the global function `name`.
+    ///
+    /// ```
+    ///     use duktape::Context;
+    ///
+    ///     let mut ctx = Context::default();
+    ///     let mut calls = 0;
+    ///     ctx.register_closure("count", move |_ctx, _args| {
+    ///         calls += 1;
+    ///         calls
+    ///     });
+    ///     let n: u32 = ctx.eval("count(); count()").unwrap();
+    ///     assert_eq!(n, 2);
+    /// ```
+    pub fn register_closure<F, R>(&mut self, name: &str, f: F)
+    where
+        F: FnMut(&mut Context, Args) -> R + 'static,
+        R: PushValue,
+    {
+        self.push_closure(f);
+        unsafe {
+            duktape_sys::duk_put_global_lstring(
+                self.inner,
+                name.as_ptr() as *const i8,
+                name.len() as u64,
+            );
+        }
+    }
+
+    // Get the closure stored on the function object at `idx`, null for
+    // objects which only inherit it and once it was dropped.
+    fn closure<F>(&mut self, idx: i32) -> *mut Stored<F> {
+        self.get_prop_bytes(idx, CLOSURE_PROP);
+        let stored = unsafe { duktape_sys::duk_get_pointer(self.inner, -1) } as *mut Stored<F>;
+        self.pop_it();
+        let holder = unsafe { duktape_sys::duk_get_heapptr(self.inner, idx) };
+        if stored.is_null() || unsafe { (*stored).owner } != holder {
+            return std::ptr::null_mut();
+        }
+        stored
+    }
+}
+
+unsafe extern "C-unwind" fn call_closure<F, R>(raw: *mut duktape_sys::duk_context) -> i32
+where
+    F: FnMut(&mut Context, Args) -> Result<R, Throw> + 'static,
+    R: PushValue,
+{
+    call_throwing(raw, |ctx| {
+        let args = Args {
+            len: ctx.stack_len(),
+        };
+        unsafe { duktape_sys::duk_push_current_function(ctx.inner) };
+        let stored = ctx.closure::<F>(-1);
+        ctx.pop_it();
+        if stored.is_null() {
+            return Err(Throw::error("closure was finalized"));
+        }
+        let closure = unsafe { &(*stored).closure };
+        let mut running = Running {
+            f: Some(closure.take().expect("closure called recursively")),
+            closure,
+        };
+        let value = (running.f.as_mut().unwrap())(ctx, args);
+        drop(running);
+        ctx.push(value?);
+        Ok(1)
+    })
+}
+
+unsafe extern "C-unwind" fn finalize_closure<F, const LOCAL: bool>(
+    raw: *mut duktape_sys::duk_context,
+) -> i32 {
+    call_native(raw, |ctx| {
+        let stored = ctx.closure::<F>(0);
+        if stored.is_null() {
+            return 0;
+        }
+        // scripts can call the finalizer themselves, keep a running closure,
+        // the real finalizer runs once the function is unreachable
+        let f = unsafe { (*stored).closure.take() };
+        if f.is_none() {
+            return 0;
+        }
+        drop(f);
+        if LOCAL {
+            ctx.heap().add_local_values(-1);
+        }
+        // a finalizer may run again if the object was rescued
+        ctx.push_pointer(std::ptr::null());
+        ctx.put_prop_bytes(0, CLOSURE_PROP);
+        drop(unsafe { Box::from_raw(stored) });
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::rc::Rc;
+
+    #[test]
+    fn captures_state() {
+        let mut ctx = Context::default();
+        let prefix = "hello ".to_string();
+        ctx.register_closure("greet", move |ctx, args| {
+            let name: String = args.get(ctx, 0).unwrap();
+            let times: Option<u32> = args.get(ctx, 1).unwrap();
+            format!("{}{}", prefix, name).repeat(times.unwrap_or(1) as usize)
+        });
+        let s: String = ctx.eval("greet('world')").unwrap();
+        assert_eq!(s, "hello world");
+        ctx.pop_it();
+        let s: String = ctx.eval("greet('x', 2)").unwrap();
+        assert_eq!(s, "hello xhello x");
+    }
+
+    #[test]
+    fn wrong_argument_type() {
+        let mut ctx = Context::default();
+        ctx.register_closure("double", |ctx, args| match args.get::<u32>(ctx, 0) {
+            Ok(n) => n * 2,
+            Err(_) => 0,
+        });
+        assert_eq!(ctx.eval::<u32>("double('x')").unwrap(), 0);
+        ctx.pop_it();
+        assert_eq!(ctx.eval::<u32>("double()").unwrap(), 0);
+        ctx.pop_it();
+        assert_eq!(ctx.eval::<u32>("double(2)").unwrap(), 4);
+    }
+
+    #[test]
+    fn usable_after_panic() {
+        let mut ctx = Context::default();
+        ctx.register_closure("check", |ctx, args| -> u32 {
+            assert!(args.get::<bool>(ctx, 0).unwrap());
+            1
+        });
+        assert!(ctx.eval::<u32>("check(false)").is_err());
+        ctx.pop_it();
+        assert_eq!(ctx.eval::<u32>("check(true)").unwrap(), 1);
+    }
+
+    #[test]
+    fn recursion_is_an_error() {
+        let mut ctx = Context::default();
+        ctx.register_closure("reenter", |ctx, _args| ctx.eval::<()>("reenter()").is_err());
+        let res = ctx.eval::<bool>("reenter()");
+        assert!(res.unwrap());
+    }
+
+    #[test]
+    fn dropped_when_collected() {
+        let state = Rc::new(());
+        let mut ctx = Context::default();
+        let captured = state.clone();
+        ctx.register_closure("f", move |_ctx, _args| Rc::strong_count(&captured) as u32);
+        assert_eq!(ctx.eval::<u32>("f()").unwrap(), 2);
+        ctx.pop_it();
+        assert_eq!(Rc::strong_count(&state), 2);
+
+        ctx.eval::<()>("f = undefined").unwrap();
+        ctx.pop_it();
+        unsafe { duktape_sys::duk_gc(ctx.inner, 0) };
+        assert_eq!(Rc::strong_count(&state), 1);
+
+        // and at the latest together with the heap
+        let captured = state.clone();
+        ctx.register_closure("g", move |_ctx, _args| {
+            let _ = &captured;
+        });
+        drop(ctx);
+        assert_eq!(Rc::strong_count(&state), 1);
+    }
+
+    #[test]
+    fn inherited_by_other_objects() {
+        let state = Rc::new(());
+        let mut ctx = Context::default();
+        let captured = state.clone();
+        ctx.register_closure("f", move |_ctx, _args| Rc::strong_count(&captured) as u32);
+
+        // objects inheriting the closure and its finalizer don't own it
+        let n: u32 = ctx
+            .eval(
+                "var child = Object.create(f);
+                Duktape.fin(f)(child);
+                child = null;
+                Duktape.gc();
+                f()",
+            )
+            .unwrap();
+        assert_eq!(n, 2);
+        ctx.pop_it();
+
+        // a running closure isn't dropped by calling its finalizer
+        ctx.register_closure("g", |ctx, _args| {
+            ctx.eval::<()>("Duktape.fin(g)(g)").unwrap();
+            ctx.pop_it();
+            1
+        });
+        assert_eq!(ctx.eval::<u32>("g() + g()").unwrap(), 2);
+        ctx.pop_it();
+
+        // other calls of the finalizer drop it, later calls throw
+        ctx.eval::<()>("Duktape.fin(f)(f)").unwrap();
+        ctx.pop_it();
+        assert_eq!(Rc::strong_count(&state), 1);
+        assert!(ctx.eval::<u32>("f()").is_err());
+    }
+
+    #[test]
+    fn panic_is_thrown() {
+        let mut ctx = Context::default();
+        ctx.register_closure("fail", |_ctx, _args| -> u32 { panic!("nope") });
+        match ctx.eval::<u32>("fail()") {
+            Err(Error::Js(e)) => assert_eq!(e.message, "nope"),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
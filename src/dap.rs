@@ -0,0 +1,672 @@
+//! A Debug Adapter Protocol server, available with the `dap` feature.
+//!
+//! [`DapServer`] lets editors debug scripts through a [`Debugger`]
+//! attached to a context. It maps the editor's breakpoints to engine
+//! breakpoints and shows the call stack of the paused engine with one
+//! scope of local variables per frame.
+//!
+//! Objects are only shown by their class and can't be expanded. The
+//! engine's commands for reading properties take raw heap pointers, which
+//! may point to freed objects once the engine ran again, for example to
+//! evaluate an expression, so they're not enabled.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::{DebugValue, Debugger, Error, ObjectClass};
+
+// How often a running engine is checked for having paused.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Scripts of a context all run on one thread.
+const THREAD_ID: u32 = 1;
+
+/// Serves one DAP client on behalf of a [`Debugger`].
+///
+/// Like the debugger, the server needs the engine to run code to answer
+/// most requests, so it runs on a thread of its own. Scripts start out
+/// paused when the debugger is attached, the server lets them continue once
+/// the client is done setting breakpoints, unless it launched or attached
+/// with `stopOnEntry`.
+///
+/// ```no_run
+///     use duktape::{Context, DapServer};
+///     use std::net::TcpListener;
+///
+///     let mut ctx = Context::default();
+///     let server = DapServer::new(ctx.attach_debugger()).source_root("/srv/scripts");
+///     let listener = TcpListener::bind("127.0.0.1:4711").unwrap();
+///     std::thread::spawn(move || server.serve_tcp(listener));
+///     // runs once an editor connected and configured its breakpoints
+///     ctx.eval::<()>("var greeting = 'hello'").unwrap();
+/// ```
+pub struct DapServer {
+    debugger: Debugger,
+    source_root: Option<PathBuf>,
+}
+
+impl DapServer {
+    pub fn new(debugger: Debugger) -> Self {
+        DapServer {
+            debugger,
+            source_root: None,
+        }
+    }
+
+    /// Directory the file names of compiled scripts are relative to, so
+    /// they can be matched with the paths the client knows them by.
+    pub fn source_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.source_root = Some(root.into());
+        self
+    }
+
+    /// Accept one client on `listener` and serve it until it disconnects.
+    pub fn serve_tcp(self, listener: TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        let res = self.serve(stream.try_clone()?, &stream);
+        // stops the thread reading requests
+        let _ = stream.shutdown(Shutdown::Both);
+        res
+    }
+
+    /// Serve a client talking through standard input and output.
+    pub fn serve_stdio(self) -> io::Result<()> {
+        self.serve(io::stdin(), io::stdout())
+    }
+
+    /// Serve a client until it disconnects or the engine goes away.
+    ///
+    /// Requests are read by a separate thread, which keeps running until
+    /// `input` is closed.
+    pub fn serve<R, W>(self, input: R, output: W) -> io::Result<()>
+    where
+        R: Read + Send + 'static,
+        W: Write,
+    {
+        let (sender, requests) = channel();
+        std::thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Ok(Some(request)) = read_message(&mut input) {
+                if sender.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut session = Session {
+            debugger: self.debugger,
+            source_root: self.source_root,
+            output,
+            seq: 0,
+            stop_on_entry: false,
+            configured: false,
+            entry: true,
+            stopped: false,
+            reason: "breakpoint",
+            breakpoints: Vec::new(),
+            next_breakpoint: 1,
+        };
+        session.run(requests)
+    }
+}
+
+struct Session<W> {
+    debugger: Debugger,
+    source_root: Option<PathBuf>,
+    output: W,
+    seq: u64,
+    stop_on_entry: bool,
+    configured: bool,
+    // the engine starts out paused, which isn't reported to the client
+    entry: bool,
+    // a stopped event was sent for the current pause
+    stopped: bool,
+    // reported for the next pause
+    reason: &'static str,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint: u64,
+}
+
+// The engine accepts breakpoints on any line, they're only verified once
+// the engine stopped on them.
+struct Breakpoint {
+    id: u64,
+    file_name: String,
+    line: u32,
+    verified: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn run(&mut self, requests: Receiver<Value>) -> io::Result<()> {
+        loop {
+            match requests.recv_timeout(POLL_INTERVAL) {
+                Ok(request) => {
+                    if !self.handle(request)? {
+                        return Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // dropping the debugger lets the scripts continue
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            // also notices an engine which went away while stopped
+            let _ = self.debugger.poll();
+            if self.debugger.is_detached() {
+                return self.event("terminated", json!({}));
+            }
+            self.check_paused()?;
+        }
+    }
+
+    // Handle a request, returns whether to keep serving.
+    fn handle(&mut self, request: Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let res = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" | "attach" => {
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                self.configured = true;
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => Ok(json!({
+                "scopes": [{
+                    "name": "Locals",
+                    "presentationHint": "locals",
+                    // references must not be 0
+                    "variablesReference": args["frameId"].as_u64().unwrap_or(0) + 1,
+                    "expensive": false,
+                }]
+            })),
+            "variables" => self.variables(args),
+            "evaluate" => self.evaluate(args),
+            "continue" => self
+                .resume(Debugger::resume, "breakpoint")
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" => self.resume(Debugger::step_over, "step"),
+            "stepIn" => self.resume(Debugger::step_into, "step"),
+            "stepOut" => self.resume(Debugger::step_out, "step"),
+            "pause" => {
+                self.reason = "pause";
+                self.debugger.pause().map(|_| Value::Null)
+            }
+            "disconnect" => {
+                self.respond(&request, Ok(Value::Null))?;
+                return Ok(false);
+            }
+            other => Err(Error::Message(format!("unsupported request {}", other))),
+        };
+        self.respond(&request, res)?;
+        if command == "initialize" {
+            self.event("initialized", json!({}))?;
+        }
+        Ok(true)
+    }
+
+    // Tell the client about a new pause.
+    fn check_paused(&mut self) -> io::Result<()> {
+        if self.stopped || self.debugger.paused().is_none() {
+            return Ok(());
+        }
+        if self.entry {
+            // hold the engine until the breakpoints are set
+            if !self.configured {
+                return Ok(());
+            }
+            self.entry = false;
+            if !self.stop_on_entry {
+                // a failure shows up as detached in the next round
+                let _ = self.debugger.resume();
+                return Ok(());
+            }
+            self.reason = "entry";
+        }
+        self.stopped = true;
+        self.verify_breakpoints()?;
+        let reason = self.reason;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    // Tell the client about the breakpoints the engine stopped on first.
+    fn verify_breakpoints(&mut self) -> io::Result<()> {
+        let frame = match self.debugger.paused() {
+            Some(frame) => frame.clone(),
+            None => return Ok(()),
+        };
+        let mut verified = Vec::new();
+        for breakpoint in &mut self.breakpoints {
+            if !breakpoint.verified
+                && breakpoint.file_name == frame.file_name
+                && breakpoint.line == frame.line_number
+            {
+                breakpoint.verified = true;
+                verified.push(
+                    json!({ "id": breakpoint.id, "verified": true, "line": breakpoint.line }),
+                );
+            }
+        }
+        for breakpoint in verified {
+            self.event(
+                "breakpoint",
+                json!({ "reason": "changed", "breakpoint": breakpoint }),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn resume(
+        &mut self,
+        run: fn(&mut Debugger) -> Result<(), Error>,
+        reason: &'static str,
+    ) -> Result<Value, Error> {
+        run(&mut self.debugger)?;
+        self.stopped = false;
+        self.reason = reason;
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, Error> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or_else(|| Error::Message("source without a path".to_string()))?;
+        let file_name = self.file_name(path);
+        let lines: Vec<u32> = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+            .map(|line| line as u32)
+            .collect();
+
+        // the client sends all breakpoints of the file, replace them, the
+        // indexes of the following breakpoints shift down on removal
+        let existing = self.debugger.breakpoints()?;
+        for (index, (name, _)) in existing.iter().enumerate().rev() {
+            if *name == file_name {
+                self.debugger.remove_breakpoint(index as u32)?;
+            }
+        }
+        self.breakpoints
+            .retain(|breakpoint| breakpoint.file_name != file_name);
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            self.debugger.add_breakpoint(&file_name, line)?;
+            let id = self.next_breakpoint;
+            self.next_breakpoint += 1;
+            breakpoints.push(json!({
+                "id": id,
+                "verified": false,
+                "line": line,
+                "message": "Not reached yet",
+            }));
+            self.breakpoints.push(Breakpoint {
+                id,
+                file_name: file_name.clone(),
+                line,
+                verified: false,
+            });
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&mut self, args: &Value) -> Result<Value, Error> {
+        let frames = self.debugger.call_stack()?;
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => frames.len(),
+        };
+        let stack_frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(depth, frame)| {
+                let name = match frame.function_name.as_str() {
+                    "" => "(anonymous)",
+                    name => name,
+                };
+                let mut stack_frame = json!({
+                    "id": depth,
+                    "name": name,
+                    "line": frame.line_number,
+                    "column": 1,
+                });
+                // native functions have no source
+                if !frame.file_name.is_empty() {
+                    stack_frame["source"] = self.source(&frame.file_name);
+                }
+                stack_frame
+            })
+            .collect();
+        Ok(json!({ "stackFrames": stack_frames, "totalFrames": frames.len() }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, Error> {
+        // see the scopes request
+        let depth = args["variablesReference"].as_u64().unwrap_or(0);
+        if depth == 0 {
+            return Ok(json!({ "variables": [] }));
+        }
+        let locals = self.debugger.locals(depth as u32 - 1)?;
+        let variables: Vec<Value> = locals
+            .iter()
+            .map(|(name, value)| {
+                json!({ "name": name, "value": display(value), "variablesReference": 0 })
+            })
+            .collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn evaluate(&mut self, args: &Value) -> Result<Value, Error> {
+        let expression = args["expression"].as_str().unwrap_or_default();
+        let value = match args["frameId"].as_u64() {
+            Some(depth) => self.debugger.eval_at(depth as u32, expression)?,
+            None => self.debugger.eval(expression)?,
+        };
+        Ok(json!({ "result": display(&value), "variablesReference": 0 }))
+    }
+
+    // File name of a script as compiled, from a path known to the client.
+    fn file_name(&self, path: &str) -> String {
+        self.source_root
+            .as_ref()
+            .and_then(|root| Path::new(path).strip_prefix(root).ok())
+            .map_or_else(
+                || path.to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            )
+    }
+
+    fn source(&self, file_name: &str) -> Value {
+        let path = match &self.source_root {
+            Some(root) => root.join(file_name),
+            None => PathBuf::from(file_name),
+        };
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        json!({ "name": name.as_deref().unwrap_or(file_name), "path": path })
+    }
+
+    fn respond(&mut self, request: &Value, res: Result<Value, Error>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": res.is_ok(),
+        });
+        match res {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(err) => response["message"] = json!(err.to_string()),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.output, &message)
+    }
+}
+
+// Format a value the way scripts would see it.
+fn display(value: &DebugValue) -> String {
+    match value {
+        DebugValue::Undefined | DebugValue::Unused => "undefined".to_string(),
+        DebugValue::Null => "null".to_string(),
+        DebugValue::Bool(b) => b.to_string(),
+        DebugValue::Number(n) if n.is_nan() => "NaN".to_string(),
+        DebugValue::Number(n) if n.is_infinite() => {
+            if *n > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+        }
+        DebugValue::Number(n) => n.to_string(),
+        DebugValue::String(s) => json!(s).to_string(),
+        DebugValue::Buffer(b) => format!("Buffer({})", b.len()),
+        DebugValue::Object { class, .. } => ObjectClass::from_raw(*class as u32)
+            .map_or("Object", ObjectClass::name)
+            .to_string(),
+        DebugValue::LightFunc { .. } => "Function".to_string(),
+        DebugValue::Pointer(p) | DebugValue::HeapPtr(p) => format!("{:#x}", p),
+    }
+}
+
+// Read a message framed by a `Content-Length` header, `None` once the input
+// is closed.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    // in one write, small writes to sockets are delayed
+    let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    output.write_all(message.as_bytes())?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompileOptions, Context};
+    use std::net::TcpStream;
+
+    struct Client {
+        input: BufReader<TcpStream>,
+        output: TcpStream,
+        seq: u64,
+        events: Vec<Value>,
+    }
+
+    impl Client {
+        fn connect(addr: std::net::SocketAddr) -> Self {
+            let output = TcpStream::connect(addr).unwrap();
+            Client {
+                input: BufReader::new(output.try_clone().unwrap()),
+                output,
+                seq: 0,
+                events: Vec::new(),
+            }
+        }
+
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut self.output, &request).unwrap();
+            loop {
+                let message = read_message(&mut self.input).unwrap().unwrap();
+                if message["type"] == "event" {
+                    self.events.push(message);
+                } else {
+                    assert_eq!(message["request_seq"], self.seq);
+                    return message;
+                }
+            }
+        }
+
+        fn body(&mut self, command: &str, arguments: Value) -> Value {
+            let response = self.request(command, arguments);
+            assert_eq!(response["success"], true, "{}", response);
+            response["body"].clone()
+        }
+
+        fn wait_event(&mut self, event: &str) -> Value {
+            loop {
+                if let Some(pos) = self.events.iter().position(|e| e["event"] == event) {
+                    return self.events.remove(pos)["body"].clone();
+                }
+                let message = read_message(&mut self.input).unwrap().unwrap();
+                assert_eq!(message["type"], "event");
+                self.events.push(message);
+            }
+        }
+    }
+
+    const SOURCE: &str = "\
+function square(x) {
+    var result = x * x;
+    return result;
+}
+var total = 0;
+for (var i = 1; i <= 3; i++) {
+    total += square(i);
+}
+total";
+
+    #[test]
+    fn debug_session() {
+        let mut ctx = Context::default();
+        let server = DapServer::new(ctx.attach_debugger()).source_root("/scripts");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || server.serve_tcp(listener));
+
+        let client = std::thread::spawn(move || {
+            let mut client = Client::connect(addr);
+            let capabilities = client.body("initialize", json!({ "adapterID": "duktape" }));
+            assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+            client.wait_event("initialized");
+            client.body("attach", json!({}));
+            let breakpoints = client.body(
+                "setBreakpoints",
+                json!({ "source": { "path": "/scripts/lib/test.js" }, "breakpoints": [{ "line": 3 }] }),
+            );
+            let breakpoint = breakpoints["breakpoints"][0].clone();
+            assert_eq!(breakpoint["verified"], false);
+            client.body("configurationDone", json!({}));
+
+            let stopped = client.wait_event("stopped");
+            assert_eq!(stopped["reason"], "breakpoint");
+            let changed = client.wait_event("breakpoint")["breakpoint"].clone();
+            assert_eq!(changed["id"], breakpoint["id"]);
+            assert_eq!(changed["verified"], true);
+            let threads = client.body("threads", json!({}));
+            assert_eq!(threads["threads"][0]["id"], THREAD_ID);
+            let trace = client.body("stackTrace", json!({ "threadId": THREAD_ID }));
+            assert_eq!(trace["totalFrames"], 2);
+            let top = &trace["stackFrames"][0];
+            assert_eq!((&top["name"], &top["line"]), (&json!("square"), &json!(3)));
+            assert_eq!(top["source"]["path"], "/scripts/lib/test.js");
+            assert_eq!(top["source"]["name"], "test.js");
+
+            let scopes = client.body("scopes", json!({ "frameId": 0 }));
+            let reference = scopes["scopes"][0]["variablesReference"].clone();
+            let variables = client.body("variables", json!({ "variablesReference": reference }));
+            let mut variables: Vec<(String, String)> = variables["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| {
+                    (
+                        v["name"].as_str().unwrap().into(),
+                        v["value"].as_str().unwrap().into(),
+                    )
+                })
+                .collect();
+            variables.sort();
+            assert_eq!(
+                variables,
+                vec![("result".into(), "1".into()), ("x".into(), "1".into())]
+            );
+
+            let res = client.body(
+                "evaluate",
+                json!({ "expression": "[x, 'y']", "frameId": 0 }),
+            );
+            assert_eq!(res["result"], "Array");
+            let res = client.body(
+                "evaluate",
+                json!({ "expression": "'i is ' + i", "frameId": 1 }),
+            );
+            assert_eq!(res["result"], "\"i is 1\"");
+            let res = client.request("evaluate", json!({ "expression": "nope", "frameId": 0 }));
+            assert_eq!(res["success"], false);
+            assert!(res["message"].as_str().unwrap().contains("ReferenceError"));
+
+            client.body("next", json!({ "threadId": THREAD_ID }));
+            assert_eq!(client.wait_event("stopped")["reason"], "step");
+            let trace = client.body("stackTrace", json!({ "threadId": THREAD_ID, "levels": 1 }));
+            assert_eq!(trace["stackFrames"].as_array().unwrap().len(), 1);
+            assert_eq!(trace["stackFrames"][0]["line"], 7);
+
+            client.body(
+                "setBreakpoints",
+                json!({ "source": { "path": "/scripts/lib/test.js" }, "breakpoints": [] }),
+            );
+            client.body("continue", json!({ "threadId": THREAD_ID }));
+            // the context is dropped once the script finished
+            client.wait_event("terminated");
+        });
+
+        let options = CompileOptions {
+            filename: Some("lib/test.js".to_string()),
+            ..Default::default()
+        };
+        let script = ctx.compile(SOURCE, options).unwrap();
+        let total: u32 = script.run(&mut ctx).unwrap();
+        assert_eq!(total, 14);
+        drop(ctx);
+        client.join().unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn display_values() {
+        assert_eq!(display(&DebugValue::Number(2.0)), "2");
+        assert_eq!(display(&DebugValue::Number(0.5)), "0.5");
+        assert_eq!(display(&DebugValue::Number(f64::NEG_INFINITY)), "-Infinity");
+        assert_eq!(display(&DebugValue::String("a\"b".into())), "\"a\\\"b\"");
+        let object = DebugValue::Object {
+            class: 3,
+            pointer: 0,
+        };
+        assert_eq!(display(&object), "Function");
+    }
+}
@@ -0,0 +1,839 @@
+//! An in-process client for the duktape debug protocol, available with the
+//! `debugger` feature.
+//!
+//! [`Context::attach_debugger`] connects the engine to a [`Debugger`]
+//! through a pair of channels. The engine only talks to the debugger while
+//! it runs code: it blocks on the channel while paused and polls it every
+//! few thousand instructions otherwise, so the debugger has to be driven
+//! from another thread than the scripts.
+
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use crate::{Context, Error};
+
+// Initial bytes of dvalues, the values making up protocol messages.
+const EOM: u8 = 0x00;
+const REQ: u8 = 0x01;
+const REP: u8 = 0x02;
+const ERR: u8 = 0x03;
+const NFY: u8 = 0x04;
+const INT4: u8 = 0x10;
+const STR4: u8 = 0x11;
+const STR2: u8 = 0x12;
+const BUF4: u8 = 0x13;
+const BUF2: u8 = 0x14;
+const UNUSED: u8 = 0x15;
+const UNDEFINED: u8 = 0x16;
+const NULL: u8 = 0x17;
+const TRUE: u8 = 0x18;
+const FALSE: u8 = 0x19;
+const NUMBER: u8 = 0x1a;
+const OBJECT: u8 = 0x1b;
+const POINTER: u8 = 0x1c;
+const LIGHTFUNC: u8 = 0x1d;
+const HEAPPTR: u8 = 0x1e;
+
+// Notifications sent by the engine.
+const NFY_STATUS: i32 = 0x01;
+const NFY_DETACHING: i32 = 0x06;
+
+// Requests sent to the engine.
+const CMD_PAUSE: i32 = 0x12;
+const CMD_RESUME: i32 = 0x13;
+const CMD_STEP_INTO: i32 = 0x14;
+const CMD_STEP_OVER: i32 = 0x15;
+const CMD_STEP_OUT: i32 = 0x16;
+const CMD_LIST_BREAK: i32 = 0x17;
+const CMD_ADD_BREAK: i32 = 0x18;
+const CMD_DEL_BREAK: i32 = 0x19;
+const CMD_GET_VAR: i32 = 0x1a;
+const CMD_GET_CALL_STACK: i32 = 0x1c;
+const CMD_GET_LOCALS: i32 = 0x1d;
+const CMD_EVAL: i32 = 0x1e;
+const CMD_DETACH: i32 = 0x1f;
+
+/// A value as seen by the debugger.
+///
+/// Objects and functions are only identified by their address, their
+/// properties aren't read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugValue {
+    Undefined,
+    /// An internal marker, e.g. for missing array elements.
+    Unused,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Buffer(Vec<u8>),
+    Object {
+        /// Duktape object class (one of `DUK_HOBJECT_CLASS_*`).
+        class: u8,
+        pointer: usize,
+    },
+    Pointer(usize),
+    LightFunc {
+        flags: u16,
+        pointer: usize,
+    },
+    HeapPtr(usize),
+}
+
+impl DebugValue {
+    fn as_int(&self) -> Result<i32, Error> {
+        match self {
+            DebugValue::Number(n) => Ok(*n as i32),
+            other => Err(protocol_error(&format!(
+                "expected an integer, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    fn into_string(self) -> String {
+        match self {
+            DebugValue::String(s) => s,
+            _ => String::new(),
+        }
+    }
+}
+
+/// A function activation, innermost first in [`Debugger::call_stack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub file_name: String,
+    pub function_name: String,
+    /// Line of the next statement to execute for the innermost frame, of
+    /// the running call for the others.
+    pub line_number: u32,
+    pub pc: u32,
+}
+
+/// A debug client attached to a [`Context`] by [`Context::attach_debugger`].
+///
+/// Requests block until the engine answers, which it only does while it
+/// runs code. Dropping the debugger detaches it and lets paused scripts
+/// continue.
+///
+/// ```
+///     use duktape::{CompileOptions, Context, DebugValue};
+///
+///     let mut ctx = Context::default();
+///     let mut debugger = ctx.attach_debugger();
+///     let client = std::thread::spawn(move || {
+///         // scripts start out paused on their first statement
+///         debugger.wait_paused().unwrap();
+///         debugger.add_breakpoint("sum.js", 4).unwrap();
+///         debugger.resume().unwrap();
+///
+///         let frame = debugger.wait_paused().unwrap();
+///         assert_eq!((frame.function_name.as_str(), frame.line_number), ("sum", 4));
+///         assert_eq!(debugger.eval("total + i").unwrap(), DebugValue::Number(10.0));
+///         debugger.detach().unwrap();
+///     });
+///
+///     let src = "function sum(n) {\n  var total = 0;\n  for (var i = 0; i < n; i++) total += i;\n  return total;\n}\nsum(4)";
+///     let options = CompileOptions { filename: Some("sum.js".into()), ..Default::default() };
+///     let script = ctx.compile(src, options).unwrap();
+///     let total: u32 = script.run(&mut ctx).unwrap();
+///     client.join().unwrap();
+///     assert_eq!(total, 6);
+/// ```
+pub struct Debugger {
+    commands: Sender<Vec<u8>>,
+    replies: Receiver<Vec<u8>>,
+    input: Vec<u8>,
+    pos: usize,
+    greeted: bool,
+    paused: Option<StackFrame>,
+    detached: bool,
+}
+
+impl Debugger {
+    /// Wait until the engine pauses, on a breakpoint, a `debugger`
+    /// statement, after a step or a [pause request](Debugger::pause).
+    ///
+    /// Returns immediately if it's already paused.
+    pub fn wait_paused(&mut self) -> Result<StackFrame, Error> {
+        loop {
+            if let Some(frame) = &self.paused {
+                return Ok(frame.clone());
+            }
+            match self.read_message()? {
+                (NFY, values) => self.notified(values),
+                _ => return Err(protocol_error("unexpected reply")),
+            }
+        }
+    }
+
+    /// Where the engine is paused, as of the last message it sent.
+    pub fn paused(&self) -> Option<&StackFrame> {
+        self.paused.as_ref()
+    }
+
+    /// Ask the engine to pause at the next statement.
+    pub fn pause(&mut self) -> Result<(), Error> {
+        self.request(Request::new(CMD_PAUSE)).map(drop)
+    }
+
+    pub fn resume(&mut self) -> Result<(), Error> {
+        self.run(CMD_RESUME)
+    }
+
+    /// Continue to the next line, entering function calls.
+    pub fn step_into(&mut self) -> Result<(), Error> {
+        self.run(CMD_STEP_INTO)
+    }
+
+    /// Continue to the next line of the current function.
+    pub fn step_over(&mut self) -> Result<(), Error> {
+        self.run(CMD_STEP_OVER)
+    }
+
+    /// Continue until the current function returns.
+    pub fn step_out(&mut self) -> Result<(), Error> {
+        self.run(CMD_STEP_OUT)
+    }
+
+    /// Pause when reaching `line` of code compiled with `file_name`,
+    /// returns the index of the breakpoint.
+    pub fn add_breakpoint(&mut self, file_name: &str, line: u32) -> Result<u32, Error> {
+        let reply = self.request(
+            Request::new(CMD_ADD_BREAK)
+                .string(file_name)
+                .int(line as i32),
+        )?;
+        Ok(reply.first().map_or(Ok(0), DebugValue::as_int)? as u32)
+    }
+
+    /// Remove a breakpoint, the indexes of the following ones shift down.
+    pub fn remove_breakpoint(&mut self, index: u32) -> Result<(), Error> {
+        self.request(Request::new(CMD_DEL_BREAK).int(index as i32))
+            .map(drop)
+    }
+
+    /// File names and lines of the breakpoints, in index order.
+    pub fn breakpoints(&mut self) -> Result<Vec<(String, u32)>, Error> {
+        let reply = self.request(Request::new(CMD_LIST_BREAK))?;
+        let mut values = reply.into_iter();
+        let mut breakpoints = Vec::new();
+        while let (Some(file), Some(line)) = (values.next(), values.next()) {
+            breakpoints.push((file.into_string(), line.as_int()? as u32));
+        }
+        Ok(breakpoints)
+    }
+
+    /// Activations of the paused engine, innermost first.
+    pub fn call_stack(&mut self) -> Result<Vec<StackFrame>, Error> {
+        let reply = self.request(Request::new(CMD_GET_CALL_STACK))?;
+        let mut values = reply.into_iter();
+        let mut frames = Vec::new();
+        while let (Some(file), Some(function), Some(line), Some(pc)) =
+            (values.next(), values.next(), values.next(), values.next())
+        {
+            frames.push(StackFrame {
+                file_name: file.into_string(),
+                function_name: function.into_string(),
+                line_number: line.as_int()? as u32,
+                pc: pc.as_int()? as u32,
+            });
+        }
+        Ok(frames)
+    }
+
+    /// Variables declared by the function at `depth` in the call stack,
+    /// 0 being the innermost. Program code has none, its variables are
+    /// globals.
+    pub fn locals(&mut self, depth: u32) -> Result<Vec<(String, DebugValue)>, Error> {
+        let reply = self.request(Request::new(CMD_GET_LOCALS).int(level(depth)))?;
+        let mut values = reply.into_iter();
+        let mut locals = Vec::new();
+        while let (Some(name), Some(value)) = (values.next(), values.next()) {
+            locals.push((name.into_string(), value));
+        }
+        Ok(locals)
+    }
+
+    /// Look up a variable in the scope of the function at `depth`, `None`
+    /// if it isn't declared.
+    pub fn variable(&mut self, depth: u32, name: &str) -> Result<Option<DebugValue>, Error> {
+        let reply = self.request(Request::new(CMD_GET_VAR).int(level(depth)).string(name))?;
+        let mut values = reply.into_iter();
+        match (values.next(), values.next()) {
+            (Some(found), Some(value)) if found.as_int()? != 0 => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Evaluate `expression` in the scope of the innermost function if the
+    /// engine is paused, in the global scope otherwise.
+    ///
+    /// Errors thrown by the expression are returned as [`Error::Message`].
+    pub fn eval(&mut self, expression: &str) -> Result<DebugValue, Error> {
+        let level = self.paused.as_ref().map(|_| level(0));
+        self.evaluate(level, expression)
+    }
+
+    /// Evaluate `expression` in the scope of the function at `depth` in the
+    /// call stack of the paused engine.
+    pub fn eval_at(&mut self, depth: u32, expression: &str) -> Result<DebugValue, Error> {
+        self.evaluate(Some(level(depth)), expression)
+    }
+
+    /// Handle the messages the engine sent so far without blocking, returns
+    /// where it's paused.
+    pub fn poll(&mut self) -> Result<Option<StackFrame>, Error> {
+        while self.has_input()? {
+            if !self.greeted {
+                self.read_greeting()?;
+                continue;
+            }
+            match self.read_message()? {
+                (NFY, values) => self.notified(values),
+                _ => return Err(protocol_error("unexpected reply")),
+            }
+        }
+        Ok(self.paused.clone())
+    }
+
+    /// Whether the engine went away or the connection was detached.
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Detach from the engine, paused scripts continue.
+    pub fn detach(mut self) -> Result<(), Error> {
+        self.request(Request::new(CMD_DETACH)).map(drop)
+    }
+
+    fn evaluate(&mut self, level: Option<i32>, expression: &str) -> Result<DebugValue, Error> {
+        let request = match level {
+            Some(level) => Request::new(CMD_EVAL).int(level),
+            None => Request::new(CMD_EVAL).null(),
+        };
+        let reply = self.request(request.string(expression))?;
+        let mut values = reply.into_iter();
+        match (values.next(), values.next()) {
+            (Some(failed), Some(value)) if failed.as_int()? == 0 => Ok(value),
+            (Some(_), Some(error)) => Err(Error::Message(error.into_string())),
+            _ => Err(protocol_error("missing eval result")),
+        }
+    }
+
+    fn run(&mut self, command: i32) -> Result<(), Error> {
+        self.request(Request::new(command))?;
+        self.paused = None;
+        Ok(())
+    }
+
+    // Send a request and wait for its reply, handling the notifications
+    // sent in the meantime.
+    fn request(&mut self, request: Request) -> Result<Vec<DebugValue>, Error> {
+        if self.detached {
+            return Err(detached());
+        }
+        let mut message = request.0;
+        message.push(EOM);
+        self.commands.send(message).map_err(|_| detached())?;
+        loop {
+            match self.read_message()? {
+                (REP, values) => return Ok(values),
+                (ERR, values) => {
+                    let message = values.into_iter().nth(1).map(DebugValue::into_string);
+                    return Err(Error::Message(format!(
+                        "debugger request failed: {}",
+                        message.unwrap_or_default()
+                    )));
+                }
+                (NFY, values) => self.notified(values),
+                _ => return Err(protocol_error("unexpected request")),
+            }
+        }
+    }
+
+    fn notified(&mut self, values: Vec<DebugValue>) {
+        let mut values = values.into_iter();
+        match values.next().map(|command| command.as_int()) {
+            Some(Ok(NFY_STATUS)) => {
+                let state = values.next().map(|state| state.as_int());
+                let frame = (|| {
+                    Ok::<_, Error>(StackFrame {
+                        file_name: values
+                            .next()
+                            .map(DebugValue::into_string)
+                            .unwrap_or_default(),
+                        function_name: values
+                            .next()
+                            .map(DebugValue::into_string)
+                            .unwrap_or_default(),
+                        line_number: values.next().map_or(Ok(0), |v| v.as_int())? as u32,
+                        pc: values.next().map_or(Ok(0), |v| v.as_int())? as u32,
+                    })
+                })();
+                self.paused = match (state, frame) {
+                    (Some(Ok(1)), Ok(frame)) => Some(frame),
+                    _ => None,
+                };
+            }
+            Some(Ok(NFY_DETACHING)) => self.detached = true,
+            // thrown errors and application notifications aren't exposed
+            _ => {}
+        }
+    }
+
+    // Read a message up to its end marker, returns its type and values.
+    fn read_message(&mut self) -> Result<(u8, Vec<DebugValue>), Error> {
+        if !self.greeted {
+            self.read_greeting()?;
+        }
+        let kind = match self.read_byte()? {
+            kind @ (REQ | REP | ERR | NFY) => kind,
+            other => return Err(protocol_error(&format!("unexpected byte {:#x}", other))),
+        };
+        let mut values = Vec::new();
+        loop {
+            match self.read_byte()? {
+                EOM => return Ok((kind, values)),
+                initial => values.push(self.read_value(initial)?),
+            }
+        }
+    }
+
+    // The engine starts with a version identification line.
+    fn read_greeting(&mut self) -> Result<(), Error> {
+        while self.read_byte()? != b'\n' {}
+        self.greeted = true;
+        Ok(())
+    }
+
+    fn read_value(&mut self, initial: u8) -> Result<DebugValue, Error> {
+        Ok(match initial {
+            INT4 => DebugValue::Number(i32::from_be_bytes(self.read_array()?) as f64),
+            STR4 => {
+                let len = u32::from_be_bytes(self.read_array()?);
+                self.read_string(len as usize)?
+            }
+            STR2 => {
+                let len = u16::from_be_bytes(self.read_array()?);
+                self.read_string(len as usize)?
+            }
+            BUF4 => {
+                let len = u32::from_be_bytes(self.read_array()?);
+                DebugValue::Buffer(self.read_bytes(len as usize)?)
+            }
+            BUF2 => {
+                let len = u16::from_be_bytes(self.read_array()?);
+                DebugValue::Buffer(self.read_bytes(len as usize)?)
+            }
+            UNUSED => DebugValue::Unused,
+            UNDEFINED => DebugValue::Undefined,
+            NULL => DebugValue::Null,
+            TRUE => DebugValue::Bool(true),
+            FALSE => DebugValue::Bool(false),
+            NUMBER => DebugValue::Number(f64::from_be_bytes(self.read_array()?)),
+            OBJECT => {
+                let class = self.read_byte()?;
+                DebugValue::Object {
+                    class,
+                    pointer: self.read_pointer()?,
+                }
+            }
+            POINTER => DebugValue::Pointer(self.read_pointer()?),
+            LIGHTFUNC => {
+                let flags = u16::from_be_bytes(self.read_array()?);
+                DebugValue::LightFunc {
+                    flags,
+                    pointer: self.read_pointer()?,
+                }
+            }
+            HEAPPTR => DebugValue::HeapPtr(self.read_pointer()?),
+            0x60..=0x7f => self.read_string((initial - 0x60) as usize)?,
+            0x80..=0xbf => DebugValue::Number((initial - 0x80) as f64),
+            0xc0..=0xff => {
+                let low = self.read_byte()?;
+                DebugValue::Number((((initial - 0xc0) as u32) << 8 | low as u32) as f64)
+            }
+            other => return Err(protocol_error(&format!("unexpected byte {:#x}", other))),
+        })
+    }
+
+    fn read_string(&mut self, len: usize) -> Result<DebugValue, Error> {
+        let bytes = self.read_bytes(len)?;
+        Ok(DebugValue::String(
+            String::from_utf8_lossy(&bytes).into_owned(),
+        ))
+    }
+
+    // Pointers are sent as a length followed by the big endian address.
+    fn read_pointer(&mut self) -> Result<usize, Error> {
+        let len = self.read_byte()?;
+        let mut pointer = 0usize;
+        for byte in self.read_bytes(len as usize)? {
+            pointer = pointer.wrapping_shl(8) | byte as usize;
+        }
+        Ok(pointer)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        for byte in &mut array {
+            *byte = self.read_byte()?;
+        }
+        Ok(array)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        (0..len).map(|_| self.read_byte()).collect()
+    }
+
+    fn has_input(&mut self) -> Result<bool, Error> {
+        if self.pos < self.input.len() {
+            return Ok(true);
+        }
+        match self.replies.try_recv() {
+            Ok(input) => {
+                self.input = input;
+                self.pos = 0;
+                Ok(true)
+            }
+            Err(TryRecvError::Empty) => Ok(false),
+            Err(TryRecvError::Disconnected) => {
+                self.detached = true;
+                Err(detached())
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        while self.pos == self.input.len() {
+            self.input = self.replies.recv().map_err(|_| {
+                self.detached = true;
+                detached()
+            })?;
+            self.pos = 0;
+        }
+        self.pos += 1;
+        Ok(self.input[self.pos - 1])
+    }
+}
+
+// Callstack levels count from -1 for the innermost activation.
+fn level(depth: u32) -> i32 {
+    -(depth as i32) - 1
+}
+
+fn detached() -> Error {
+    Error::Message("debugger is detached".to_string())
+}
+
+fn protocol_error(what: &str) -> Error {
+    Error::Message(format!("debug protocol error: {}", what))
+}
+
+struct Request(Vec<u8>);
+
+impl Request {
+    fn new(command: i32) -> Self {
+        Request(vec![REQ]).int(command)
+    }
+
+    fn int(mut self, value: i32) -> Self {
+        match value {
+            0..=0x3f => self.0.push(0x80 + value as u8),
+            0x40..=0x3fff => self.0.extend([0xc0 + (value >> 8) as u8, value as u8]),
+            _ => {
+                self.0.push(INT4);
+                self.0.extend(value.to_be_bytes());
+            }
+        }
+        self
+    }
+
+    fn string(mut self, value: &str) -> Self {
+        match value.len() {
+            len @ 0..=0x1f => self.0.push(0x60 + len as u8),
+            len @ 0x20..=0xffff => {
+                self.0.push(STR2);
+                self.0.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                self.0.push(STR4);
+                self.0.extend((len as u32).to_be_bytes());
+            }
+        }
+        self.0.extend(value.as_bytes());
+        self
+    }
+
+    fn null(mut self) -> Self {
+        self.0.push(NULL);
+        self
+    }
+}
+
+// The engine's end of the connection, owned by the heap while attached.
+struct Transport {
+    incoming: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    outgoing: Sender<Vec<u8>>,
+    written: Vec<u8>,
+}
+
+impl Transport {
+    fn flush(&mut self) {
+        if !self.written.is_empty() {
+            // a closed channel is noticed by the next read
+            let _ = self.outgoing.send(std::mem::take(&mut self.written));
+        }
+    }
+}
+
+unsafe extern "C-unwind" fn read_cb(
+    udata: *mut std::ffi::c_void,
+    buffer: *mut std::os::raw::c_char,
+    length: duktape_sys::duk_size_t,
+) -> duktape_sys::duk_size_t {
+    let transport = &mut *(udata as *mut Transport);
+    // the client may be waiting for what was written so far
+    transport.flush();
+    while transport.pos == transport.chunk.len() {
+        match transport.incoming.recv() {
+            Ok(chunk) => {
+                transport.chunk = chunk;
+                transport.pos = 0;
+            }
+            // reading nothing detaches the debugger
+            Err(_) => return 0,
+        }
+    }
+    let n = (length as usize).min(transport.chunk.len() - transport.pos);
+    std::ptr::copy_nonoverlapping(
+        transport.chunk.as_ptr().add(transport.pos),
+        buffer as *mut u8,
+        n,
+    );
+    transport.pos += n;
+    n as duktape_sys::duk_size_t
+}
+
+unsafe extern "C-unwind" fn write_cb(
+    udata: *mut std::ffi::c_void,
+    buffer: *const std::os::raw::c_char,
+    length: duktape_sys::duk_size_t,
+) -> duktape_sys::duk_size_t {
+    let transport = &mut *(udata as *mut Transport);
+    let bytes = std::slice::from_raw_parts(buffer as *const u8, length as usize);
+    transport.written.extend_from_slice(bytes);
+    length
+}
+
+unsafe extern "C-unwind" fn peek_cb(udata: *mut std::ffi::c_void) -> duktape_sys::duk_size_t {
+    let transport = &mut *(udata as *mut Transport);
+    if transport.pos == transport.chunk.len() {
+        match transport.incoming.try_recv() {
+            Ok(chunk) => {
+                transport.chunk = chunk;
+                transport.pos = 0;
+            }
+            Err(TryRecvError::Empty) => return 0,
+            // make the engine read, which detaches
+            Err(TryRecvError::Disconnected) => return 1,
+        }
+    }
+    (transport.chunk.len() - transport.pos) as duktape_sys::duk_size_t
+}
+
+unsafe extern "C-unwind" fn write_flush_cb(udata: *mut std::ffi::c_void) {
+    (*(udata as *mut Transport)).flush();
+}
+
+unsafe extern "C-unwind" fn detached_cb(
+    _ctx: *mut duktape_sys::duk_context,
+    udata: *mut std::ffi::c_void,
+) {
+    // deliver the detaching notification before hanging up
+    let mut transport = Box::from_raw(udata as *mut Transport);
+    transport.flush();
+}
+
+impl Context {
+    /// Attach a [`Debugger`], detaching the one attached before.
+    ///
+    /// The engine starts out paused, the next script stops before its first
+    /// statement until the debugger resumes it.
+    pub fn attach_debugger(&mut self) -> Debugger {
+        let (commands, incoming) = channel();
+        let (outgoing, replies) = channel();
+        let transport = Box::new(Transport {
+            incoming,
+            chunk: Vec::new(),
+            pos: 0,
+            outgoing,
+            written: Vec::new(),
+        });
+        unsafe {
+            duktape_sys::duk_debugger_detach(self.inner);
+            duktape_sys::duk_debugger_attach(
+                self.inner,
+                Some(read_cb),
+                Some(write_cb),
+                Some(peek_cb),
+                None,
+                Some(write_flush_cb),
+                None,
+                Some(detached_cb),
+                Box::into_raw(transport) as *mut _,
+            );
+        }
+        Debugger {
+            commands,
+            replies,
+            input: Vec::new(),
+            pos: 0,
+            greeted: false,
+            paused: None,
+            detached: false,
+        }
+    }
+
+    /// Detach the debugger, if any. Paused scripts continue.
+    pub fn detach_debugger(&mut self) {
+        unsafe { duktape_sys::duk_debugger_detach(self.inner) };
+    }
+
+    /// Handle pending debugger requests while no code is running.
+    ///
+    /// The engine only talks to the debugger while it runs code, hosts which
+    /// are idle between scripts should call this now and then.
+    pub fn debugger_cooperate(&mut self) {
+        unsafe { duktape_sys::duk_debugger_cooperate(self.inner) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompileOptions;
+
+    const SOURCE: &str = "\
+function square(x) {
+    var result = x * x;
+    return result;
+}
+var total = 0;
+for (var i = 1; i <= 3; i++) {
+    total += square(i);
+}
+total";
+
+    fn run_debugged<F>(client: F) -> u32
+    where
+        F: FnOnce(Debugger) + Send + 'static,
+    {
+        let mut ctx = Context::default();
+        let debugger = ctx.attach_debugger();
+        let client = std::thread::spawn(move || client(debugger));
+        let options = CompileOptions {
+            filename: Some("test.js".to_string()),
+            ..Default::default()
+        };
+        let script = ctx.compile(SOURCE, options).unwrap();
+        let total = script.run(&mut ctx).unwrap();
+        client.join().unwrap();
+        total
+    }
+
+    #[test]
+    fn breakpoints_and_locals() {
+        let total = run_debugged(|mut debugger| {
+            let start = debugger.wait_paused().unwrap();
+            assert_eq!(start.file_name, "test.js");
+            assert_eq!(debugger.add_breakpoint("test.js", 3).unwrap(), 0);
+            assert_eq!(
+                debugger.breakpoints().unwrap(),
+                vec![("test.js".to_string(), 3)]
+            );
+            debugger.resume().unwrap();
+
+            let frame = debugger.wait_paused().unwrap();
+            assert_eq!(
+                (frame.function_name.as_str(), frame.line_number),
+                ("square", 3)
+            );
+            let mut locals = debugger.locals(0).unwrap();
+            locals.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                locals,
+                vec![
+                    ("result".to_string(), DebugValue::Number(1.0)),
+                    ("x".to_string(), DebugValue::Number(1.0)),
+                ]
+            );
+            let stack = debugger.call_stack().unwrap();
+            assert_eq!(stack.len(), 2);
+            assert_eq!(
+                (stack[1].function_name.as_str(), stack[1].line_number),
+                ("global", 7)
+            );
+            assert_eq!(
+                debugger.variable(1, "i").unwrap(),
+                Some(DebugValue::Number(1.0))
+            );
+            assert_eq!(debugger.variable(0, "nope").unwrap(), None);
+
+            debugger.remove_breakpoint(0).unwrap();
+            assert_eq!(debugger.breakpoints().unwrap(), vec![]);
+            debugger.resume().unwrap();
+        });
+        assert_eq!(total, 14);
+    }
+
+    #[test]
+    fn stepping() {
+        let total = run_debugged(|mut debugger| {
+            debugger.wait_paused().unwrap();
+            debugger.add_breakpoint("test.js", 7).unwrap();
+            debugger.resume().unwrap();
+            assert_eq!(debugger.wait_paused().unwrap().line_number, 7);
+
+            debugger.step_into().unwrap();
+            let frame = debugger.wait_paused().unwrap();
+            assert_eq!(
+                (frame.function_name.as_str(), frame.line_number),
+                ("square", 2)
+            );
+            debugger.step_over().unwrap();
+            assert_eq!(debugger.wait_paused().unwrap().line_number, 3);
+            debugger.step_out().unwrap();
+            assert_eq!(debugger.wait_paused().unwrap().function_name, "global");
+            debugger.detach().unwrap();
+        });
+        assert_eq!(total, 14);
+    }
+
+    #[test]
+    fn eval_in_paused_scope() {
+        let total = run_debugged(|mut debugger| {
+            debugger.wait_paused().unwrap();
+            debugger.add_breakpoint("test.js", 3).unwrap();
+            debugger.resume().unwrap();
+            debugger.wait_paused().unwrap();
+
+            assert_eq!(
+                debugger.eval("result + ' from ' + x").unwrap(),
+                DebugValue::String("1 from 1".to_string())
+            );
+            // assignments are seen by the script
+            debugger.eval("result = 100").unwrap();
+            assert!(matches!(
+                debugger.eval("[]").unwrap(),
+                DebugValue::Object { .. }
+            ));
+            let err = debugger.eval("missing()").unwrap_err();
+            assert!(err.to_string().contains("ReferenceError"), "{}", err);
+            // dropping the client detaches it
+        });
+        assert_eq!(total, 100 + 4 + 9);
+    }
+}
@@ -0,0 +1,138 @@
+//! Errors thrown by scripts.
+
+use std::fmt;
+
+use crate::value::{peek_dynamic, JsValue};
+use crate::Context;
+
+/// An error thrown by a script and not caught by it.
+///
+/// Fields which don't apply to the thrown value are left empty, e.g.
+/// `throw {code: 3}` only has `value` and a `message` made by `ToString`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsError {
+    /// Constructor name like `TypeError`, `None` for non-`Error` values.
+    pub name: Option<String>,
+    pub message: String,
+    /// Stack trace including the name and message, as formatted by duktape.
+    pub stack: Option<String>,
+    pub file_name: Option<String>,
+    pub line_number: Option<u32>,
+    /// Duktape error code (one of `DUK_ERR_*`), `0` for non-`Error` values.
+    pub code: i32,
+    /// The thrown value itself.
+    pub value: JsValue,
+    text: String,
+}
+
+impl JsError {
+    /// Read the error on top of the stack, leaving it in place.
+    pub(crate) fn from_stack(ctx: &mut Context) -> Self {
+        let text = safe_string(ctx, -1);
+        let mut error = JsError {
+            name: None,
+            message: text.clone(),
+            stack: None,
+            file_name: None,
+            line_number: None,
+            code: 0,
+            value: JsValue::Undefined,
+            text,
+        };
+        // property getters may throw too, read them in protected calls
+        // which don't report what they throw
+        for read in [read_value, read_error] {
+            unsafe {
+                duktape_sys::duk_dup(ctx.as_raw(), -1);
+                duktape_sys::duk_safe_call(
+                    ctx.as_raw(),
+                    Some(read),
+                    &mut error as *mut JsError as *mut _,
+                    1,
+                    1,
+                );
+                duktape_sys::duk_pop(ctx.as_raw());
+            }
+        }
+        error
+    }
+
+    /// Whether this is the error thrown by the engine when it ran out of
+    /// memory, or the fixed one it falls back to when creating that failed.
+    pub(crate) fn is_alloc_failure(&self) -> bool {
+        match self.name.as_deref() {
+            Some("DoubleError") => true,
+            _ => self.code == duktape_sys::DUK_ERR_ERROR as i32 && self.message == "alloc failed",
+        }
+    }
+}
+
+unsafe extern "C-unwind" fn read_value(
+    raw: *mut duktape_sys::duk_context,
+    udata: *mut std::ffi::c_void,
+) -> duktape_sys::duk_ret_t {
+    let error = &mut *(udata as *mut JsError);
+    let ctx = &mut std::mem::ManuallyDrop::new(Context::from_raw(raw));
+    error.value = peek_dynamic(ctx, -1);
+    0
+}
+
+unsafe extern "C-unwind" fn read_error(
+    raw: *mut duktape_sys::duk_context,
+    udata: *mut std::ffi::c_void,
+) -> duktape_sys::duk_ret_t {
+    let error = &mut *(udata as *mut JsError);
+    let ctx = &mut std::mem::ManuallyDrop::new(Context::from_raw(raw));
+
+    error.code = duktape_sys::duk_get_error_code(raw, -1) as i32;
+    if error.code == 0 {
+        return 0;
+    }
+    error.name = string_prop(ctx, "name");
+    if let Some(message) = string_prop(ctx, "message") {
+        error.message = message;
+    }
+    error.file_name = string_prop(ctx, "fileName");
+    ctx.get_prop(-1, "lineNumber");
+    // scripts may set it to anything, converting that could throw
+    if duktape_sys::duk_is_number(raw, -1) != 0 {
+        error.line_number = Some(ctx.get_uint(-1));
+    }
+    ctx.pop_it();
+
+    duktape_sys::duk_dup(raw, -1);
+    duktape_sys::duk_to_stacktrace(raw, -1);
+    error.stack = Some(safe_string(ctx, -1));
+    ctx.pop_it();
+    0
+}
+
+fn string_prop(ctx: &mut Context, name: &str) -> Option<String> {
+    ctx.get_prop(-1, name);
+    let value = match unsafe { duktape_sys::duk_is_string(ctx.as_raw(), -1) } {
+        0 => None,
+        _ => Some(safe_string(ctx, -1)),
+    };
+    ctx.pop_it();
+    value
+}
+
+fn safe_string(ctx: &mut Context, idx: i32) -> String {
+    unsafe {
+        duktape_sys::duk_dup(ctx.as_raw(), idx);
+        let mut len = 0;
+        let ptr = duktape_sys::duk_safe_to_lstring(ctx.as_raw(), -1, &mut len);
+        let slice = std::slice::from_raw_parts(ptr as *const u8, len as usize);
+        let string = String::from_utf8_lossy(slice).into_owned();
+        duktape_sys::duk_pop(ctx.as_raw());
+        string
+    }
+}
+
+impl fmt::Display for JsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl std::error::Error for JsError {}
@@ -0,0 +1,56 @@
+//! Recovery from fatal engine errors.
+//!
+//! Duktape calls the fatal handler for errors it cannot throw to a catcher,
+//! e.g. an error raised outside of any protected call, and requires that
+//! the handler never returns. Instead of aborting, the handler marks the
+//! heap as poisoned, reports the error to the callback set with
+//! [`ContextBuilder::fatal_handler`](crate::ContextBuilder::fatal_handler)
+//! and unwinds with a [`FatalError`] payload. The protected entry points
+//! like [`Context::eval`](crate::Context::eval) turn it into
+//! [`Error::Fatal`](crate::Error::Fatal), after which every call returns
+//! [`Error::Poisoned`](crate::Error::Poisoned).
+
+use std::ffi::{c_void, CStr};
+use std::fmt;
+
+use crate::heap::HeapState;
+
+/// Panic payload used to unwind out of a fatal error.
+///
+/// Fatal errors raised by the unprotected stack methods (e.g. `get_uint`
+/// called on a string outside of a native function) reach the caller as a
+/// panic carrying this payload.
+#[derive(Debug)]
+pub struct FatalError {
+    message: String,
+}
+
+impl FatalError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for FatalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fatal duktape error: {}", self.message)
+    }
+}
+
+impl std::error::Error for FatalError {}
+
+pub(crate) type FatalHandler = Box<dyn Fn(&str)>;
+
+pub(crate) unsafe extern "C-unwind" fn fatal(udata: *mut c_void, msg: *const i8) {
+    let message = if msg.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(msg).to_string_lossy().into_owned()
+    };
+    let state = &*(udata as *const HeapState);
+    state.poisoned.set(true);
+    if let Some(handler) = &state.on_fatal {
+        handler(&message);
+    }
+    std::panic::resume_unwind(Box::new(FatalError { message }))
+}
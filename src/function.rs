@@ -0,0 +1,223 @@
+//! Calling JS functions from Rust.
+
+use crate::value::{PeekError, PeekValue, PushValue};
+use crate::{Context, Error, JsRef};
+
+/// Arguments of a function call, implemented for tuples of [`PushValue`]s
+/// and for `Vec`s of arguments of the same type.
+pub trait PushArgs {
+    /// Push the arguments, returning how many were pushed.
+    fn push_args(self, ctx: &mut Context) -> i32;
+}
+
+macro_rules! tuple_args {
+    ($($arg: ident),*) => {
+        impl<$($arg: PushValue),*> PushArgs for ($($arg,)*) {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn push_args(self, ctx: &mut Context) -> i32 {
+                let ($($arg,)*) = self;
+                let mut n = 0;
+                $(
+                    ctx.push($arg);
+                    n += 1;
+                )*
+                n
+            }
+        }
+    };
+}
+
+tuple_args!();
+tuple_args!(A);
+tuple_args!(A, B);
+tuple_args!(A, B, C);
+tuple_args!(A, B, C, D);
+tuple_args!(A, B, C, D, E);
+tuple_args!(A, B, C, D, E, F);
+tuple_args!(A, B, C, D, E, F, G);
+tuple_args!(A, B, C, D, E, F, G, H);
+
+impl<T: PushValue> PushArgs for Vec<T> {
+    fn push_args(self, ctx: &mut Context) -> i32 {
+        let n = self.len() as i32;
+        for arg in self {
+            ctx.push(arg);
+        }
+        n
+    }
+}
+
+/// A JS function kept alive by a [`JsRef`].
+///
+/// Calls leave the value stack as they found it, whether they succeed or not.
+///
+/// ```
+///     use duktape::{Context, JsFunction};
+///
+///     let mut ctx = Context::default();
+///     let add: JsFunction = ctx.eval("(function(a, b) { return a + b })").unwrap();
+///     ctx.pop().unwrap();
+///
+///     let sum: u32 = add.call(&mut ctx, (1u32, 2u32)).unwrap();
+///     assert_eq!(sum, 3);
+/// ```
+#[derive(Clone)]
+pub struct JsFunction {
+    function: JsRef,
+}
+
+impl JsFunction {
+    /// Call the function with `this` set to `undefined`.
+    pub fn call<A, R>(&self, ctx: &mut Context, args: A) -> Result<R, Error>
+    where
+        A: PushArgs,
+        R: PeekValue,
+    {
+        if ctx.is_poisoned() {
+            return Err(Error::Poisoned);
+        }
+        ctx.push(&self.function);
+        let n_args = args.push_args(ctx);
+        let rc = ctx.protect(|ctx| Ok(unsafe { duktape_sys::duk_pcall(ctx.inner, n_args) }))?;
+        take_result(ctx, rc)
+    }
+
+    /// Call the function as a method of `this`.
+    pub fn call_method<T, A, R>(&self, ctx: &mut Context, this: T, args: A) -> Result<R, Error>
+    where
+        T: PushValue,
+        A: PushArgs,
+        R: PeekValue,
+    {
+        if ctx.is_poisoned() {
+            return Err(Error::Poisoned);
+        }
+        ctx.push(&self.function);
+        ctx.push(this);
+        let n_args = args.push_args(ctx);
+        let rc =
+            ctx.protect(|ctx| Ok(unsafe { duktape_sys::duk_pcall_method(ctx.inner, n_args) }))?;
+        take_result(ctx, rc)
+    }
+}
+
+// Pop the return value or error left by a protected call.
+fn take_result<R: PeekValue>(ctx: &mut Context, rc: i32) -> Result<R, Error> {
+    if rc != 0 {
+        let error = ctx.take_error();
+        ctx.pop_it();
+        return Err(error);
+    }
+    ctx.peek_protected()?.map_err(Error::Peek)
+}
+
+impl AsRef<JsRef> for JsFunction {
+    fn as_ref(&self) -> &JsRef {
+        &self.function
+    }
+}
+
+impl PushValue for &JsFunction {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        ctx.push(&self.function)
+    }
+}
+
+impl PeekValue for JsFunction {
+    fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
+        if unsafe { duktape_sys::duk_is_function(ctx.inner, idx) } == 0 {
+            return Err(PeekError::Type("function"));
+        }
+        Ok(JsFunction {
+            function: JsRef::new(ctx, idx),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JsObject;
+
+    #[test]
+    fn call() {
+        let mut ctx = Context::default();
+        let global = JsObject::global(&mut ctx);
+        ctx.eval::<()>("function greet(name, n) { return 'hi ' + name + n }")
+            .unwrap();
+        ctx.pop_it();
+
+        let greet: JsFunction = global.get(&mut ctx, "greet").unwrap();
+        let s: String = greet.call(&mut ctx, ("bob".to_string(), 1u32)).unwrap();
+        assert_eq!(s, "hi bob1");
+        let s: String = greet.call(&mut ctx, vec![1u32, 2]).unwrap();
+        assert_eq!(s, "hi 12");
+        let _: () = greet.call(&mut ctx, ()).unwrap();
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn call_method() {
+        let mut ctx = Context::default();
+        let obj: JsObject = ctx.eval("({base: 10})").unwrap();
+        ctx.pop_it();
+        let add: JsFunction = ctx.eval("(function(n) { return this.base + n })").unwrap();
+        ctx.pop_it();
+
+        let n: u32 = add.call_method(&mut ctx, &obj, (5u32,)).unwrap();
+        assert_eq!(n, 15);
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn errors_keep_stack_balanced() {
+        let mut ctx = Context::default();
+        let fail: JsFunction = ctx
+            .eval("(function(x) { if (x) throw new TypeError('bad'); return [] })")
+            .unwrap();
+        ctx.pop_it();
+
+        match fail.call::<_, ()>(&mut ctx, (true,)) {
+            Err(Error::Js(e)) => assert_eq!(e.name.as_deref(), Some("TypeError")),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(
+            fail.call::<_, u32>(&mut ctx, (false,)),
+            Err(Error::Js(_))
+        ));
+        assert!(matches!(
+            fail.call::<_, JsFunction>(&mut ctx, (false,)),
+            Err(Error::Peek(PeekError::Type("function")))
+        ));
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn poisoned_keeps_stack_balanced() {
+        use crate as duktape;
+        use crate::duktape;
+
+        #[duktape]
+        fn boom(ctx: &mut Context) {
+            let msg = std::ffi::CString::new("boom").unwrap();
+            unsafe { duktape_sys::duk_fatal_raw(ctx.as_raw(), msg.as_ptr()) }
+        }
+
+        let mut ctx = Context::default();
+        let f: JsFunction = ctx.eval("(function(x) { return x })").unwrap();
+        ctx.pop_it();
+        ctx.register_function("boom", Boom);
+        assert!(matches!(ctx.eval::<()>("boom()"), Err(Error::Fatal(_))));
+
+        let len = ctx.stack_len();
+        assert!(matches!(
+            f.call::<_, u32>(&mut ctx, (1u32,)),
+            Err(Error::Poisoned)
+        ));
+        assert!(matches!(
+            f.call_method::<_, _, u32>(&mut ctx, (), (1u32,)),
+            Err(Error::Poisoned)
+        ));
+        assert_eq!(ctx.stack_len(), len);
+    }
+}
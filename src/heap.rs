@@ -0,0 +1,101 @@
+//! Per-heap state shared between a [`Context`](crate::Context) and the
+//! callbacks duktape invokes on its behalf.
+//!
+//! The state is boxed and passed to `duk_create_heap` as heap udata, so it
+//! can be recovered from any raw `duk_context` belonging to the heap, even
+//! from inside native functions where only the raw pointer is available.
+
+use std::any::TypeId;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::alloc::Memory;
+use crate::fatal::FatalHandler;
+use crate::timers::Timers;
+
+/// Context of a heap, or null once the heap is destroyed.
+pub(crate) type HeapLink = Rc<Cell<*mut duktape_sys::duk_context>>;
+
+pub(crate) struct HeapState {
+    pub(crate) memory: Memory,
+    pub(crate) poisoned: Cell<bool>,
+    pub(crate) on_fatal: Option<FatalHandler>,
+    /// Shared with handles to values kept alive in the heap stash, which
+    /// need a context to release them and must not outlive the heap.
+    pub(crate) link: HeapLink,
+    next_slot: Cell<u32>,
+    /// Stash keys released by dropped handles, reused before new ones.
+    free_slots: RefCell<Vec<u32>>,
+    /// Number of Rust values owned by the heap which aren't `Send`, like
+    /// closures pushed with `Context::push_closure` or the fatal handler.
+    pub(crate) local_values: Cell<usize>,
+    /// Errors returned to Rust callers, counted for `ContextPool`. Failed
+    /// type checks of values peeked by the crate aren't.
+    pub(crate) errors: Cell<u64>,
+    /// Heap stash slots of the prototypes made by `Context::push_prototype`.
+    pub(crate) prototypes: RefCell<HashMap<TypeId, u32>>,
+    /// Pending `setTimeout` and `setInterval` callbacks, once installed.
+    pub(crate) timers: RefCell<Option<Timers>>,
+    #[cfg(feature = "exec-timeout")]
+    pub(crate) deadline: crate::timeout::Deadline,
+}
+
+impl HeapState {
+    pub(crate) fn new(memory: Memory, on_fatal: Option<FatalHandler>) -> Self {
+        let local_values = Cell::new(on_fatal.is_some() as usize);
+        HeapState {
+            memory,
+            poisoned: Cell::new(false),
+            on_fatal,
+            link: Rc::new(Cell::new(std::ptr::null_mut())),
+            next_slot: Cell::new(0),
+            free_slots: RefCell::new(Vec::new()),
+            local_values,
+            errors: Cell::new(0),
+            prototypes: RefCell::new(HashMap::new()),
+            timers: RefCell::new(None),
+            #[cfg(feature = "exec-timeout")]
+            deadline: Default::default(),
+        }
+    }
+
+    /// Reserve a key in the heap stash.
+    pub(crate) fn new_slot(&self) -> u32 {
+        if let Some(slot) = self.free_slots.borrow_mut().pop() {
+            return slot;
+        }
+        let slot = self.next_slot.get();
+        self.next_slot
+            .set(slot.checked_add(1).expect("heap stash slots exhausted"));
+        slot
+    }
+
+    /// Return a key whose value was deleted from the heap stash.
+    pub(crate) fn release_slot(&self, slot: u32) {
+        self.free_slots.borrow_mut().push(slot);
+    }
+
+    pub(crate) fn add_local_values(&self, n: isize) {
+        self.local_values
+            .set(self.local_values.get().wrapping_add_signed(n));
+    }
+
+    /// Forget about failures recorded by previous calls into the engine.
+    pub(crate) fn reset(&self) {
+        self.memory.take_exhausted();
+        #[cfg(feature = "exec-timeout")]
+        self.deadline.take_expired();
+    }
+}
+
+impl HeapState {
+    /// # Safety
+    ///
+    /// `ctx` must belong to a heap created by [`ContextBuilder`](crate::ContextBuilder).
+    pub(crate) unsafe fn from_ctx<'a>(ctx: *mut duktape_sys::duk_context) -> &'a HeapState {
+        let mut funcs = std::mem::MaybeUninit::<duktape_sys::duk_memory_functions>::uninit();
+        duktape_sys::duk_get_memory_functions(ctx, funcs.as_mut_ptr());
+        &*(funcs.assume_init().udata as *const HeapState)
+    }
+}
@@ -59,26 +59,94 @@
 //!     assert_eq!(sum, 6);
 //! ```
 
-use std::ffi::CStr;
+use std::panic::AssertUnwindSafe;
 use thiserror::Error;
 
+pub use builder::ContextBuilder;
+pub use class::JsClass;
+pub use closure::Args;
+#[cfg(feature = "dap")]
+pub use dap::DapServer;
+#[cfg(feature = "debugger")]
+pub use debugger::{DebugValue, Debugger, StackFrame};
 pub use duktape_macros::{duktape, Value};
 #[doc(hidden)]
 pub use duktape_sys as sys;
-pub use value::{PeekValue, PushValue};
-
+pub use error::JsError;
+pub use fatal::FatalError;
+pub use function::{JsFunction, PushArgs};
+pub use module::{ModuleResolver, NativeModule};
+#[doc(hidden)]
+pub use native::call_native;
+pub use object::JsObject;
+pub use pool::{ContextPool, ContextPoolBuilder, PoolMetrics, PooledContext, Reuse};
+pub use promise::{JsPromise, PromiseState, Resolver};
+pub use realm::Realm;
+pub use reference::JsRef;
+pub use script::{CompileOptions, Script};
+pub use send::SendContext;
+pub use stats::{HeapStats, ObjectClass, ValueInfo, ValueType};
+pub use thread::{JsThread, Resumed, Yields};
+pub use timers::{Clock, ManualClock, SystemClock};
+pub use value::{JsValue, PeekValue, PushValue};
+
+mod alloc;
+mod builder;
+mod bytecode;
+mod class;
+mod closure;
+#[cfg(feature = "dap")]
+mod dap;
+#[cfg(feature = "debugger")]
+mod debugger;
+mod error;
+mod fatal;
+mod function;
+mod heap;
+mod module;
+mod native;
+mod object;
+mod pool;
+mod promise;
+mod realm;
+mod reference;
+mod script;
+mod send;
 pub mod serialize;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod thread;
+#[cfg(feature = "exec-timeout")]
+mod timeout;
+mod timers;
 pub mod value;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("{}", .0)]
     Message(String),
+    /// A script threw an error which it didn't catch.
+    #[error("{}", .0)]
+    Js(Box<JsError>),
     #[error("{}", .0)]
     Peek(#[source] value::PeekError),
+    #[error("out of memory")]
+    OutOfMemory,
+    /// The script ran past its deadline, see `Context::set_deadline`.
+    #[error("execution timed out")]
+    Timeout,
+    #[error("fatal error: {}", .0)]
+    Fatal(String),
+    /// Bytecode was dumped by a different build of the engine or is corrupted.
+    #[error("invalid bytecode")]
+    InvalidBytecode,
+    /// A previous call ran into a fatal error, the heap can't be used anymore.
+    #[error("context is poisoned by a fatal error")]
+    Poisoned,
 }
 
-type CFunction = unsafe extern "C" fn(*mut duktape_sys::duk_context) -> i32;
+type CFunction = unsafe extern "C-unwind" fn(*mut duktape_sys::duk_context) -> i32;
 
 pub trait Function {
     const ARGS: i32;
@@ -165,17 +233,59 @@ impl Context {
     }
 
     pub fn call_function<F: Function>(&mut self, f: F) -> Result<(), Error> {
-        let rv = unsafe { f.ptr()(self.inner) };
-        if rv < 0 {
-            return Err(Error::Message("function failed".to_string()));
-        }
-        Ok(())
+        self.protect(|ctx| {
+            let rv = unsafe { f.ptr()(ctx.inner) };
+            if rv < 0 {
+                return Err(Error::Message("function failed".to_string()));
+            }
+            Ok(())
+        })
     }
 
     pub fn peek<T: PeekValue>(&mut self, idx: i32) -> Result<T, value::PeekError> {
         T::peek_at(self, idx)
     }
 
+    /// Set the prototype of the object at `idx` to the value on top of the
+    /// stack, which is popped.
+    pub fn set_prototype(&mut self, idx: duktape_sys::duk_idx_t) {
+        unsafe { duktape_sys::duk_set_prototype(self.inner, idx) }
+    }
+
+    /// Use the function on top of the stack, which is popped, as getter of
+    /// the property `name` of the object at `obj_id`. A setter defined
+    /// before is kept.
+    pub fn put_getter(&mut self, obj_id: duktape_sys::duk_idx_t, name: &str) {
+        self.put_accessor(obj_id, name, duktape_sys::DUK_DEFPROP_HAVE_GETTER);
+    }
+
+    /// Like [`Context::put_getter`], for the setter.
+    pub fn put_setter(&mut self, obj_id: duktape_sys::duk_idx_t, name: &str) {
+        self.put_accessor(obj_id, name, duktape_sys::DUK_DEFPROP_HAVE_SETTER);
+    }
+
+    fn put_accessor(&mut self, obj_id: duktape_sys::duk_idx_t, name: &str, flags: u32) {
+        use duktape_sys::{DUK_DEFPROP_HAVE_ENUMERABLE, DUK_DEFPROP_SET_CONFIGURABLE};
+
+        let obj_id = unsafe { duktape_sys::duk_normalize_index(self.inner, obj_id) };
+        self.push_string(name);
+        unsafe {
+            duktape_sys::duk_insert(self.inner, -2);
+            // non-enumerable like class accessors, and configurable so the
+            // other half can be added later
+            duktape_sys::duk_def_prop(
+                self.inner,
+                obj_id,
+                flags | DUK_DEFPROP_SET_CONFIGURABLE | DUK_DEFPROP_HAVE_ENUMERABLE,
+            );
+        }
+    }
+
+    /// Whether the running native function was called with `new`.
+    pub fn is_constructor_call(&mut self) -> bool {
+        unsafe { duktape_sys::duk_is_constructor_call(self.inner) != 0 }
+    }
+
     pub fn put_global_string(&mut self, value: &str) {
         unsafe {
             duktape_sys::duk_put_global_lstring(
@@ -266,31 +376,176 @@ impl Context {
             DUK_COMPILE_EVAL, DUK_COMPILE_NOFILENAME, DUK_COMPILE_NOSOURCE, DUK_COMPILE_SAFE,
         };
 
-        let rv = unsafe {
-            duktape_sys::duk_eval_raw(
-                self.inner,
-                value.as_ptr() as *const i8,
-                value.len() as u64,
-                DUK_COMPILE_EVAL | DUK_COMPILE_NOSOURCE | DUK_COMPILE_NOFILENAME | DUK_COMPILE_SAFE,
-            )
-        };
-        if rv != 0 {
-            let mut len = 0;
-            let ptr = unsafe { duktape_sys::duk_safe_to_lstring(self.inner, -1, &mut len) };
-            let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
-            let str = std::str::from_utf8(slice).unwrap();
-            Err(Error::Message(str.to_owned()))
-        } else {
-            self.peek(-1).map_err(Error::Peek)
+        self.protect(|ctx| {
+            let rv = unsafe {
+                duktape_sys::duk_eval_raw(
+                    ctx.inner,
+                    value.as_ptr() as *const i8,
+                    value.len() as u64,
+                    DUK_COMPILE_EVAL
+                        | DUK_COMPILE_NOSOURCE
+                        | DUK_COMPILE_NOFILENAME
+                        | DUK_COMPILE_SAFE,
+                )
+            };
+            if rv != 0 {
+                Err(ctx.take_error())
+            } else {
+                ctx.peek(-1).map_err(Error::Peek)
+            }
+        })
+    }
+
+    /// Returns whether the heap was poisoned by a fatal error.
+    pub fn is_poisoned(&self) -> bool {
+        self.heap().poisoned.get()
+    }
+
+    /// Bytes currently allocated by the heap.
+    pub fn memory_used(&self) -> usize {
+        self.heap().memory.allocated()
+    }
+
+    fn heap(&self) -> &heap::HeapState {
+        unsafe { heap::HeapState::from_ctx(self.inner) }
+    }
+
+    // Run a call into the engine, turning a fatal error into `Error::Fatal`.
+    fn protect<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.is_poisoned() {
+            return Err(Error::Poisoned);
+        }
+        self.heap().reset();
+        match std::panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(res) => res,
+            Err(payload) => match payload.downcast::<FatalError>() {
+                Ok(fatal) => Err(Error::Fatal(fatal.message().to_owned())),
+                Err(payload) => std::panic::resume_unwind(payload),
+            },
         }
     }
 
+    // Run `f` in a protected call consuming the top `n_args` values, so
+    // errors thrown by the engine (including failed `duk_require_*` checks)
+    // are returned instead of being fatal. Whatever `f` leaves on the stack
+    // is discarded.
+    fn safe_call<F, R>(&mut self, n_args: i32, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Context) -> R,
+    {
+        enum Outcome<F, R> {
+            Pending(F),
+            Done(R),
+            Panicked(Box<dyn std::any::Any + Send>),
+        }
+
+        unsafe extern "C-unwind" fn trampoline<F, R>(
+            raw: *mut duktape_sys::duk_context,
+            udata: *mut std::ffi::c_void,
+        ) -> duktape_sys::duk_ret_t
+        where
+            F: FnOnce(&mut Context) -> R,
+        {
+            let outcome = &mut *(udata as *mut Outcome<F, R>);
+            let f = match std::mem::replace(outcome, Outcome::Panicked(Box::new(()))) {
+                Outcome::Pending(f) => f,
+                _ => unreachable!(),
+            };
+            let ctx = &mut std::mem::ManuallyDrop::new(Context::from_raw(raw));
+            // a panic must not unwind through the engine, rethrow it once
+            // the protected call returned
+            *outcome = match std::panic::catch_unwind(AssertUnwindSafe(|| f(ctx))) {
+                Ok(res) => Outcome::Done(res),
+                Err(payload) if payload.is::<FatalError>() => std::panic::resume_unwind(payload),
+                Err(payload) => Outcome::Panicked(payload),
+            };
+            0
+        }
+
+        self.protect(|ctx| {
+            let mut outcome = Outcome::Pending(f);
+            let rc = unsafe {
+                duktape_sys::duk_safe_call(
+                    ctx.inner,
+                    Some(trampoline::<F, R>),
+                    &mut outcome as *mut Outcome<F, R> as *mut _,
+                    n_args,
+                    1,
+                )
+            };
+            let res = if rc != 0 {
+                Err(ctx.take_error())
+            } else {
+                match outcome {
+                    Outcome::Done(res) => Ok(res),
+                    Outcome::Panicked(payload) => {
+                        ctx.pop_it();
+                        std::panic::resume_unwind(payload)
+                    }
+                    Outcome::Pending(_) => unreachable!(),
+                }
+            };
+            ctx.pop_it();
+            res
+        })
+    }
+
+    // Peek the value on top of the stack, which is consumed, inside a
+    // protected call as type checks throw. A failed check is for the caller
+    // to handle and doesn't count as an error of the context's scripts.
+    fn peek_protected<T: PeekValue>(&mut self) -> Result<Result<T, value::PeekError>, Error> {
+        let errors = self.heap().errors.get();
+        let value = self.safe_call(1, |ctx| ctx.peek(-1));
+        self.heap().errors.set(errors);
+        value
+    }
+
+    // Convert the error left on top of the stack by a failed protected call.
+    fn take_error(&mut self) -> Error {
+        // a refused allocation may have been recovered from, only report
+        // it when the engine actually gave up
+        let heap = self.heap();
+        heap.errors.set(heap.errors.get() + 1);
+        let exhausted = heap.memory.take_exhausted();
+        // reading the error makes protected calls, which reset the flags
+        #[cfg(feature = "exec-timeout")]
+        let expired = self.heap().deadline.take_expired();
+        let error = JsError::from_stack(self);
+        if exhausted && error.is_alloc_failure() {
+            return Error::OutOfMemory;
+        }
+        #[cfg(feature = "exec-timeout")]
+        if expired {
+            return Error::Timeout;
+        }
+        Error::Js(Box::new(error))
+    }
+
     fn pop_it(&mut self) {
         unsafe {
             duktape_sys::duk_pop(self.inner);
         }
     }
 
+    // Read the heap stash property `key`, which scripts can't reach.
+    fn get_stashed<T: PeekValue>(&mut self, key: &[u8]) -> Result<T, Error> {
+        unsafe { duktape_sys::duk_push_heap_stash(self.inner) };
+        self.get_prop_bytes(-1, key);
+        let value = self.peek(-1);
+        self.pop_n(2);
+        value.map_err(Error::Peek)
+    }
+
+    // Move the value on top of the stack into the heap stash as `key`.
+    fn put_stashed(&mut self, key: &[u8]) {
+        unsafe {
+            duktape_sys::duk_push_heap_stash(self.inner);
+            duktape_sys::duk_swap_top(self.inner, -2);
+        }
+        self.put_prop_bytes(-2, key);
+        self.pop_it();
+    }
+
     pub fn pop(&mut self) -> Result<(), Error> {
         self.pop_value::<()>().map_err(Error::Peek)
     }
@@ -314,17 +569,14 @@ impl Context {
     }
 
     pub fn call(&mut self, n_args: duktape_sys::duk_idx_t) -> Result<(), Error> {
-        let rc = unsafe { duktape_sys::duk_pcall(self.inner, n_args) };
-        if rc == 0 {
-            Ok(())
-        } else {
-            let mut len = 0;
-            let err =
-                unsafe { duktape_sys::duk_safe_to_lstring(self.inner, -1, &mut len) } as *const u8;
-            let msg = unsafe { std::slice::from_raw_parts(err, len as usize) };
-            let str = std::str::from_utf8(msg).unwrap().to_owned();
-            Err(Error::Message(str))
-        }
+        self.protect(|ctx| {
+            let rc = unsafe { duktape_sys::duk_pcall(ctx.inner, n_args) };
+            if rc == 0 {
+                Ok(())
+            } else {
+                Err(ctx.take_error())
+            }
+        })
     }
 
     pub fn call_prop(
@@ -332,17 +584,14 @@ impl Context {
         obj_id: duktape_sys::duk_idx_t,
         n_args: duktape_sys::duk_idx_t,
     ) -> Result<(), Error> {
-        let rc = unsafe { duktape_sys::duk_pcall_prop(self.inner, obj_id, n_args) };
-        if rc == 0 {
-            Ok(())
-        } else {
-            let mut len = 0;
-            let err =
-                unsafe { duktape_sys::duk_safe_to_lstring(self.inner, -1, &mut len) } as *const u8;
-            let msg = unsafe { std::slice::from_raw_parts(err, len as usize) };
-            let str = std::str::from_utf8(msg).unwrap().to_owned();
-            Err(Error::Message(str))
-        }
+        self.protect(|ctx| {
+            let rc = unsafe { duktape_sys::duk_pcall_prop(ctx.inner, obj_id, n_args) };
+            if rc == 0 {
+                Ok(())
+            } else {
+                Err(ctx.take_error())
+            }
+        })
     }
 
     pub fn get_global_str(&mut self, value: &str) -> bool {
@@ -450,21 +699,27 @@ impl Context {
 
 impl Default for Context {
     fn default() -> Self {
-        extern "C" fn fatal(_udata: *mut std::ffi::c_void, msg: *const i8) {
-            let msg = unsafe { CStr::from_ptr(msg) };
-            panic!("{:?}", msg.to_str());
-        }
-        Context {
-            inner: unsafe {
-                duktape_sys::duk_create_heap(None, None, None, std::ptr::null_mut(), Some(fatal))
-            },
-        }
+        ContextBuilder::new()
+            .build()
+            .expect("failed to create duktape heap")
     }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
-        unsafe { duktape_sys::duk_destroy_heap(self.inner) }
+        self.heap().link.set(std::ptr::null_mut());
+        if self.is_poisoned() {
+            // the heap may be in an inconsistent state after a fatal error,
+            // leak it rather than risk crashing while tearing it down
+            self.inner = std::ptr::null_mut();
+            return;
+        }
+        let state = self.heap() as *const heap::HeapState;
+        unsafe {
+            duktape_sys::duk_destroy_heap(self.inner);
+            // the allocator callbacks use the state until the heap is gone
+            drop(Box::from_raw(state as *mut heap::HeapState));
+        }
         self.inner = std::ptr::null_mut();
     }
 }
@@ -472,17 +727,18 @@ impl Drop for Context {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ffi::CStr;
 
     #[test]
     fn c_stuff() {
-        extern "C" fn fatal(_udata: *mut std::ffi::c_void, msg: *const i8) {
+        extern "C-unwind" fn fatal(_udata: *mut std::ffi::c_void, msg: *const i8) {
             let msg = unsafe { CStr::from_ptr(msg) };
             panic!("{:?}", msg.to_str());
         }
         unsafe {
             use duktape_sys::{DUK_COMPILE_EVAL, DUK_COMPILE_NOFILENAME, DUK_COMPILE_NOSOURCE};
 
-            extern "C" fn print(ctx: *mut duktape_sys::duk_context) -> i32 {
+            extern "C-unwind" fn print(ctx: *mut duktape_sys::duk_context) -> i32 {
                 let value = " ";
                 unsafe {
                     let _ = duktape_sys::duk_push_lstring(
@@ -576,4 +832,148 @@ mod tests {
         //ctx.eval("print('hello', 1);");
         //ctx.pop();
     }
+
+    #[test]
+    fn js_error() {
+        let mut ctx = Context::default();
+        let err = match ctx.eval::<()>("\n  null.foo") {
+            Err(Error::Js(err)) => err,
+            res => panic!("unexpected result {:?}", res),
+        };
+        assert_eq!(err.name.as_deref(), Some("TypeError"));
+        assert_eq!(err.code, duktape_sys::DUK_ERR_TYPE_ERROR as i32);
+        assert_eq!(err.line_number, Some(2));
+        assert!(err.stack.as_ref().unwrap().starts_with(&err.to_string()));
+        ctx.pop_it();
+
+        let err = match ctx.eval::<()>("throw {code: 3}") {
+            Err(Error::Js(err)) => err,
+            res => panic!("unexpected result {:?}", res),
+        };
+        assert_eq!(err.name, None);
+        assert_eq!(err.code, 0);
+        assert_eq!(err.message, "[object Object]");
+        let mut props = std::collections::BTreeMap::new();
+        props.insert("code".to_string(), JsValue::Number(3.0));
+        assert_eq!(err.value, JsValue::Object(props));
+        ctx.pop_it();
+
+        ctx.eval::<()>("function fail() { throw new RangeError('bad') }")
+            .unwrap();
+        ctx.pop_it();
+        ctx.get_global_str("fail");
+        match ctx.call(0) {
+            Err(Error::Js(err)) => {
+                assert_eq!(err.to_string(), "RangeError: bad");
+                assert_eq!(err.message, "bad");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        ctx.pop_it();
+
+        // a line number which isn't a number is left out
+        match ctx.eval::<()>(
+            "var e = new Error('odd'); Object.defineProperty(e, 'lineNumber', { value: 'x' }); throw e",
+        ) {
+            Err(Error::Js(err)) => {
+                assert_eq!(err.line_number, None);
+                assert_eq!(err.message, "odd");
+                assert!(err.stack.is_some());
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        ctx.pop_it();
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut ctx = ContextBuilder::new()
+            .memory_limit(512 * 1024)
+            .build()
+            .unwrap();
+        let res = ctx.eval::<()>("var a = []; while (true) { a.push('item' + a.length) }");
+        assert!(matches!(res, Err(Error::OutOfMemory)));
+        ctx.pop_it();
+
+        // scripts may catch the failure themselves
+        let caught = ctx
+            .eval::<bool>(
+                "var b = []; try { while (true) { b.push({}) } } catch (e) { b = null; true }",
+            )
+            .unwrap();
+        assert!(caught);
+        ctx.pop_it();
+
+        // the heap stays usable once the garbage is gone
+        let x: u32 = ctx.eval("a = null; 1 + 2").unwrap();
+        assert_eq!(x, 3);
+    }
+
+    #[test]
+    fn memory_limit_recovered() {
+        let mut ctx = ContextBuilder::new()
+            .memory_limit(512 * 1024)
+            .build()
+            .unwrap();
+        // a later unrelated error isn't blamed on the earlier failure
+        let res = ctx.eval::<()>(
+            "var b = []; try { while (true) { b.push({}) } } catch (e) { b = null }
+             null.missing",
+        );
+        match res {
+            Err(Error::Js(err)) => assert_eq!(err.name.as_deref(), Some("TypeError")),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn memory_limit_too_small() {
+        let res = ContextBuilder::new().memory_limit(1024).build();
+        assert!(matches!(res, Err(Error::OutOfMemory)));
+    }
+
+    #[test]
+    fn fatal_error_poisons_context() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let reported = Rc::new(RefCell::new(None));
+        let mut ctx = ContextBuilder::new()
+            .fatal_handler({
+                let reported = reported.clone();
+                move |msg| *reported.borrow_mut() = Some(msg.to_owned())
+            })
+            .build()
+            .unwrap();
+
+        // a type error thrown outside of any protected call is fatal
+        ctx.push_string("not a number");
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| ctx.get_uint(-1)));
+        let payload = res.unwrap_err();
+        assert!(payload.downcast_ref::<FatalError>().is_some());
+        assert!(reported.borrow().is_some());
+
+        assert!(ctx.is_poisoned());
+        assert!(matches!(ctx.eval::<u32>("1 + 2"), Err(Error::Poisoned)));
+    }
+
+    #[test]
+    fn fatal_error_in_native_function() {
+        use crate as duktape;
+
+        #[duktape]
+        fn boom(ctx: &mut Context) {
+            let msg = std::ffi::CString::new("boom").unwrap();
+            unsafe { duktape_sys::duk_fatal_raw(ctx.as_raw(), msg.as_ptr()) }
+        }
+
+        let mut ctx = Context::default();
+        ctx.register_function("boom", Boom);
+        match ctx.eval::<()>("boom()") {
+            Err(Error::Fatal(msg)) => assert_eq!(msg, "boom"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(ctx.call_function(Boom), Err(Error::Poisoned)));
+    }
 }
@@ -0,0 +1,479 @@
+//! CommonJS modules loaded through a host provided resolver.
+//!
+//! `require` itself is written in JS so errors thrown by a module reach
+//! the requiring script unchanged. The host only resolves ids and loads
+//! sources, each module is compiled as a function with its id as the
+//! filename, so stack traces and error locations point into the module.
+//!
+//! Modules implemented in Rust are registered by name with
+//! [`Context::register_module`] and take precedence over the resolver.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::closure::Args;
+use crate::native::Throw;
+use crate::value::{PeekValue, PushValue};
+use crate::{CompileOptions, Context, Error, Function, JsFunction, JsObject, JsRef};
+
+const REQUIRE_PROP: &[u8] = b"\xffrequire";
+// factories of native modules by name
+const NATIVES_PROP: &[u8] = b"\xffnatives";
+
+const REQUIRE: &str = "(function (natives, resolve, load) {
+    var cache = Object.create(null);
+    var hasOwn = Object.prototype.hasOwnProperty;
+
+    function dirname(id) {
+        var at = id.lastIndexOf('/');
+        return at < 0 ? '' : id.substring(0, at);
+    }
+
+    function makeRequire(referrer) {
+        var require = function (specifier) {
+            specifier = String(specifier);
+            var native = hasOwn.call(natives, specifier);
+            var id = native ? specifier : resolve(specifier, referrer);
+            if (typeof id !== 'string') {
+                throw id;
+            }
+            var module = cache[id];
+            if (module) {
+                // a module required again while it runs gets its
+                // exports so far, like in node
+                return module.exports;
+            }
+            module = { id: id, filename: id, exports: {}, loaded: false };
+            cache[id] = module;
+            try {
+                if (native) {
+                    module.exports = natives[id]();
+                } else {
+                    var fn = load(id);
+                    if (typeof fn !== 'function') {
+                        throw fn;
+                    }
+                    fn.call(module.exports, module.exports, makeRequire(id), module, id, dirname(id));
+                }
+            } catch (e) {
+                delete cache[id];
+                throw e;
+            }
+            module.loaded = true;
+            return module.exports;
+        };
+        require.cache = cache;
+        return require;
+    }
+
+    return makeRequire;
+})";
+
+/// Finds the sources of modules for [`Context::install_require`].
+///
+/// Errors are thrown to the calling script as an `Error` with the error's
+/// message.
+pub trait ModuleResolver {
+    /// Turn the argument of a `require` call in the module `referrer`, or
+    /// in a script if `None`, into a module id.
+    ///
+    /// By default ids are `/` separated paths: specifiers starting with
+    /// `./` or `../` are relative to the directory of the referrer, others
+    /// are used as they are.
+    fn resolve(&self, specifier: &str, referrer: Option<&str>) -> Result<String, Error> {
+        if !specifier.starts_with("./") && !specifier.starts_with("../") {
+            return Ok(specifier.to_string());
+        }
+        let mut path: Vec<&str> = referrer.unwrap_or("").split('/').collect();
+        // the referrer's own name
+        path.pop();
+        for part in specifier.split('/') {
+            match part {
+                "." | "" => {}
+                ".." => {
+                    if path.pop().is_none() {
+                        return Err(Error::Message(format!(
+                            "module '{}' is outside of the root",
+                            specifier
+                        )));
+                    }
+                }
+                part => path.push(part),
+            }
+        }
+        Ok(path.join("/"))
+    }
+
+    /// Get the source of the module `id`.
+    fn load(&self, id: &str) -> Result<String, Error>;
+}
+
+// Used by `require` installed for native modules only.
+struct NoModules;
+
+impl ModuleResolver for NoModules {
+    fn load(&self, id: &str) -> Result<String, Error> {
+        Err(Error::Message(format!("cannot find module '{}'", id)))
+    }
+}
+
+/// Modules bundled in memory, by id.
+impl ModuleResolver for HashMap<String, String> {
+    fn load(&self, id: &str) -> Result<String, Error> {
+        self.get(id)
+            .cloned()
+            .ok_or_else(|| Error::Message(format!("cannot find module '{}'", id)))
+    }
+}
+
+impl Context {
+    /// Add the `require` global loading modules through `resolver`.
+    ///
+    /// ```
+    ///     use std::collections::HashMap;
+    ///     use duktape::Context;
+    ///
+    ///     let mut modules = HashMap::new();
+    ///     modules.insert("math/add".to_string(), "exports.add = function (a, b) { return a + b }".to_string());
+    ///     modules.insert("main".to_string(), "module.exports = require('./math/add').add(1, 2)".to_string());
+    ///
+    ///     let mut ctx = Context::default();
+    ///     ctx.install_require(modules).unwrap();
+    ///     assert_eq!(ctx.require::<u32>("main").unwrap(), 3);
+    /// ```
+    pub fn install_require<R: ModuleResolver + 'static>(
+        &mut self,
+        resolver: R,
+    ) -> Result<(), Error> {
+        let resolver = Rc::new(resolver);
+        let script = self.compile(REQUIRE, CompileOptions::default())?;
+        let init: JsFunction = script.run(self)?;
+        self.pop_it();
+
+        let r = resolver.clone();
+        // both return an error object for `require` to throw
+        self.push_closure(move |ctx, args| {
+            let resolved = args
+                .get::<String>(ctx, 0)
+                .map_err(Error::Peek)
+                .and_then(|specifier| {
+                    let referrer: Option<String> = args.get(ctx, 1).map_err(Error::Peek)?;
+                    r.resolve(&specifier, referrer.as_deref())
+                });
+            match resolved {
+                Ok(id) => ctx.push_string(&id),
+                Err(err) => Throw::from(err).push(ctx),
+            }
+            JsRef::from_top(ctx)
+        });
+        let resolve: JsFunction = self.pop_value().map_err(Error::Peek)?;
+        self.push_closure(move |ctx, args| {
+            let loaded = args
+                .get::<String>(ctx, 0)
+                .map_err(Error::Peek)
+                .and_then(|id| {
+                    let source = resolver.load(&id)?;
+                    Ok((id, source))
+                });
+            match loaded {
+                Ok((id, source)) => compile_module(ctx, &id, &source),
+                Err(err) => {
+                    Throw::from(err).push(ctx);
+                    JsRef::from_top(ctx)
+                }
+            }
+        });
+        let load: JsFunction = self.pop_value().map_err(Error::Peek)?;
+
+        let natives = natives(self, true)?;
+        let make_require: JsFunction = init.call(self, (&natives, &resolve, &load))?;
+        let require: JsFunction = make_require.call(self, ())?;
+        JsObject::global(self).set(self, "require", &require)?;
+        self.push(&require);
+        self.put_stashed(REQUIRE_PROP);
+        Ok(())
+    }
+
+    /// Make `module` available to `require` as `name`.
+    ///
+    /// The exports object is created when the module is first required.
+    /// A `require` without any other modules is installed if there is none
+    /// yet, install the resolver first when using both.
+    pub fn register_module(&mut self, name: &str, module: NativeModule) -> Result<(), Error> {
+        if self.require_function()?.is_none() {
+            self.install_require(NoModules)?;
+        }
+        let natives = natives(self, false)?;
+        self.push_closure(move |ctx, _args| {
+            let exports = JsObject::new(ctx);
+            for (name, push) in &module.members {
+                push(ctx);
+                let value = JsRef::from_top(ctx);
+                exports.set(ctx, name, &value).unwrap();
+            }
+            exports.as_ref().clone()
+        });
+        let factory: JsFunction = self.pop_value().map_err(Error::Peek)?;
+        natives.set(self, name, &factory)
+    }
+
+    /// Require the module `specifier` from Rust, returning its exports.
+    pub fn require<T: PeekValue>(&mut self, specifier: &str) -> Result<T, Error> {
+        let require = self
+            .require_function()?
+            .ok_or_else(|| Error::Message("require isn't installed".to_string()))?;
+        require.call(self, (specifier.to_string(),))
+    }
+
+    fn require_function(&mut self) -> Result<Option<JsFunction>, Error> {
+        self.get_stashed(REQUIRE_PROP)
+    }
+}
+
+// The registry of native modules, shared by all `require` functions.
+fn natives(ctx: &mut Context, create: bool) -> Result<JsObject, Error> {
+    if create && ctx.get_stashed::<Option<JsObject>>(NATIVES_PROP)?.is_none() {
+        unsafe { duktape_sys::duk_push_bare_object(ctx.inner) };
+        ctx.put_stashed(NATIVES_PROP);
+    }
+    ctx.get_stashed(NATIVES_PROP)
+}
+
+type Member = Box<dyn Fn(&mut Context)>;
+
+/// Exports of a module implemented in Rust, see [`Context::register_module`].
+///
+/// ```
+///     use duktape::{duktape, Context, NativeModule};
+///
+///     #[duktape]
+///     fn add(ctx: &mut Context) -> u32 {
+///         ctx.get_uint(0) + ctx.get_uint(1)
+///     }
+///
+///     let mut ctx = Context::default();
+///     let math = NativeModule::new()
+///         .function("add", Add)
+///         .constant("answer", 42u32)
+///         .closure("greet", |ctx, args| format!("hi {}", args.get::<u32>(ctx, 0).unwrap()));
+///     ctx.register_module("host:math", math).unwrap();
+///
+///     let s: String = ctx
+///         .eval("var math = require('host:math'); math.greet(math.add(1, 2) + math.answer)")
+///         .unwrap();
+///     assert_eq!(s, "hi 45");
+/// ```
+#[derive(Default)]
+pub struct NativeModule {
+    members: Vec<(String, Member)>,
+}
+
+impl NativeModule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Export a function generated by the [`duktape`](crate::duktape) attribute.
+    pub fn function<F: Function>(self, name: &str, f: F) -> Self {
+        let ptr = f.ptr();
+        self.member(name, move |ctx| unsafe {
+            duktape_sys::duk_push_c_function(ctx.inner, Some(ptr), F::ARGS);
+        })
+    }
+
+    /// Export a closure, see [`Context::push_closure`].
+    pub fn closure<F, R>(self, name: &str, f: F) -> Self
+    where
+        F: FnMut(&mut Context, Args) -> R + Clone + 'static,
+        R: PushValue,
+    {
+        self.member(name, move |ctx| ctx.push_closure(f.clone()))
+    }
+
+    pub fn constant<T: PushValue + Clone + 'static>(self, name: &str, value: T) -> Self {
+        self.member(name, move |ctx| {
+            ctx.push(value.clone());
+        })
+    }
+
+    fn member(mut self, name: &str, push: impl Fn(&mut Context) + 'static) -> Self {
+        self.members.push((name.to_string(), Box::new(push)));
+        self
+    }
+}
+
+// Compile a module into a function, or an error object for `require` to
+// throw, so syntax errors keep their type.
+fn compile_module(ctx: &mut Context, id: &str, source: &str) -> JsRef {
+    use duktape_sys::{DUK_COMPILE_FUNCTION, DUK_COMPILE_NOSOURCE, DUK_COMPILE_SAFE};
+
+    // on a single line, so line numbers match the source
+    let wrapped = format!(
+        "function (exports, require, module, __filename, __dirname) {{{}\n}}",
+        source
+    );
+    ctx.push_string(id);
+    unsafe {
+        duktape_sys::duk_compile_raw(
+            ctx.inner,
+            wrapped.as_ptr() as *const i8,
+            wrapped.len() as u64,
+            // the filename is the single argument of the protected call
+            DUK_COMPILE_SAFE | DUK_COMPILE_NOSOURCE | DUK_COMPILE_FUNCTION | 1,
+        )
+    };
+    JsRef::from_top(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::eval;
+
+    fn context(modules: &[(&str, &str)]) -> Context {
+        let modules: HashMap<String, String> = modules
+            .iter()
+            .map(|(id, source)| (id.to_string(), source.to_string()))
+            .collect();
+        let mut ctx = Context::default();
+        ctx.install_require(modules).unwrap();
+        ctx
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let modules: HashMap<String, String> = HashMap::new();
+        assert_eq!(modules.resolve("./b", Some("a/main")).unwrap(), "a/b");
+        assert_eq!(
+            modules.resolve("../b/./c", Some("a/x/main")).unwrap(),
+            "a/b/c"
+        );
+        assert_eq!(modules.resolve("./b", None).unwrap(), "b");
+        assert_eq!(modules.resolve("lib/b", Some("a/main")).unwrap(), "lib/b");
+        assert!(modules.resolve("../b", Some("main")).is_err());
+    }
+
+    #[test]
+    fn cached_with_paths() {
+        let mut ctx = context(&[
+            (
+                "app/main",
+                "var counter = require('./lib/counter');
+                counter.increment();
+                module.exports = {
+                    count: require('./lib/counter').count,
+                    dir: counter.dir,
+                    file: __filename
+                }",
+            ),
+            (
+                "app/lib/counter",
+                "exports.count = 0;
+                exports.dir = __dirname;
+                exports.increment = function () { exports.count++ }",
+            ),
+        ]);
+        let main: JsObject = ctx.require("app/main").unwrap();
+        assert_eq!(main.get::<u32>(&mut ctx, "count").unwrap(), 1);
+        assert_eq!(main.get::<String>(&mut ctx, "dir").unwrap(), "app/lib");
+        assert_eq!(main.get::<String>(&mut ctx, "file").unwrap(), "app/main");
+        assert!(ctx
+            .eval::<bool>("require('app/main') === require('app/main')")
+            .unwrap());
+    }
+
+    #[test]
+    fn cycles() {
+        let mut ctx = context(&[
+            (
+                "a",
+                "exports.done = false;
+                var b = require('./b');
+                exports.seen = b.seen;
+                exports.done = true",
+            ),
+            (
+                "b",
+                "var a = require('./a');
+                exports.seen = a.done;
+                exports.done = true",
+            ),
+        ]);
+        let a: JsObject = ctx.require("a").unwrap();
+        assert!(a.get::<bool>(&mut ctx, "done").unwrap());
+        assert!(!a.get::<bool>(&mut ctx, "seen").unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        let mut ctx = context(&[
+            ("main", "require('./lib/broken')"),
+            ("lib/broken", "var ok = 1;\nthrow new TypeError('broken')"),
+            ("lib/syntax", "var ok = 1;\nvar = 2"),
+        ]);
+        match ctx.require::<()>("main") {
+            Err(Error::Js(e)) => {
+                assert_eq!(e.name.as_deref(), Some("TypeError"));
+                assert_eq!(e.file_name.as_deref(), Some("lib/broken"));
+                assert_eq!(e.line_number, Some(2));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        // failed modules aren't cached
+        assert!(ctx.require::<()>("lib/broken").is_err());
+        assert!(!eval::<bool>(&mut ctx, "'main' in require.cache"));
+
+        match ctx.require::<()>("lib/syntax") {
+            Err(Error::Js(e)) => assert_eq!(e.name.as_deref(), Some("SyntaxError")),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match ctx.require::<()>("missing") {
+            Err(Error::Js(e)) => assert_eq!(e.message, "cannot find module 'missing'"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(ctx.stack_len(), 0);
+
+        // scripts can catch failed lookups
+        let caught: String = eval(
+            &mut ctx,
+            "var caught = [];
+                ['missing', '../outside'].forEach(function (id) {
+                    try { require(id) } catch (e) { caught.push(e instanceof Error) }
+                });
+                caught.join()",
+        );
+        assert_eq!(caught, "true,true");
+    }
+
+    #[test]
+    fn native_modules() {
+        let mut ctx = context(&[(
+            "lib/user",
+            "var db = require('host:db');
+            exports.name = db.find(db.ADMIN)",
+        )]);
+        let names = ["root".to_string(), "bob".to_string()];
+        let db = NativeModule::new()
+            .constant("ADMIN", 0u32)
+            .closure("find", move |ctx, args| {
+                names[args.get::<u32>(ctx, 0).unwrap() as usize].clone()
+            });
+        ctx.register_module("host:db", db).unwrap();
+
+        let user: JsObject = ctx.require("lib/user").unwrap();
+        assert_eq!(user.get::<String>(&mut ctx, "name").unwrap(), "root");
+        assert!(eval::<bool>(
+            &mut ctx,
+            "require('host:db') === require('host:db')"
+        ));
+        assert!(ctx.require::<()>("host:missing").is_err());
+
+        // without a resolver
+        let mut ctx = Context::default();
+        ctx.register_module("host:empty", NativeModule::new())
+            .unwrap();
+        let keys = ctx.require::<JsObject>("host:empty").unwrap();
+        assert!(keys.keys(&mut ctx).unwrap().is_empty());
+        assert!(ctx.require::<()>("./file").is_err());
+    }
+}
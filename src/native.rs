@@ -0,0 +1,122 @@
+//! Support code for native functions generated by the `duktape` attribute.
+
+use std::any::Any;
+use std::mem::ManuallyDrop;
+use std::panic::AssertUnwindSafe;
+
+use crate::value::PeekError;
+use crate::{Context, Error, FatalError};
+
+/// An error for a native function to throw once its Rust frames are gone.
+#[derive(Debug)]
+pub(crate) struct Throw {
+    code: u32,
+    message: String,
+}
+
+impl Throw {
+    pub(crate) fn error(message: impl Into<String>) -> Self {
+        Throw {
+            code: duktape_sys::DUK_ERR_ERROR,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn type_error(message: impl Into<String>) -> Self {
+        Throw {
+            code: duktape_sys::DUK_ERR_TYPE_ERROR,
+            message: message.into(),
+        }
+    }
+
+    /// Push the error object without throwing it.
+    pub(crate) fn push(self, ctx: &mut Context) {
+        ctx.push_string(&self.message);
+        unsafe { push_error(ctx.inner, self.code) };
+    }
+}
+
+impl From<Error> for Throw {
+    fn from(err: Error) -> Self {
+        Throw::error(err.to_string())
+    }
+}
+
+impl From<PeekError> for Throw {
+    fn from(err: PeekError) -> Self {
+        Throw::error(err.to_string())
+    }
+}
+
+/// Run the body of a native function, rethrowing a Rust panic as a JS `Error`.
+///
+/// Unwinding must not cross the C frames of the engine, so the panic is
+/// caught here and its message thrown with `duk_throw`, which scripts can
+/// catch like any other error.
+///
+/// # Safety
+///
+/// Must only be called from a function invoked by duktape with `raw` as
+/// its context.
+#[doc(hidden)]
+pub unsafe fn call_native<F>(raw: *mut duktape_sys::duk_context, f: F) -> i32
+where
+    F: FnOnce(&mut Context) -> i32,
+{
+    call_throwing(raw, |ctx| Ok(f(ctx)))
+}
+
+/// Like [`call_native`], also throwing the error returned by `f`.
+pub(crate) unsafe fn call_throwing<F>(raw: *mut duktape_sys::duk_context, f: F) -> i32
+where
+    F: FnOnce(&mut Context) -> Result<i32, Throw>,
+{
+    let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // prevent drop
+        let ctx = &mut ManuallyDrop::new(Context::from_raw(raw));
+        f(ctx)
+    }));
+    let payload = match res {
+        Ok(Ok(rc)) => return rc,
+        Ok(Err(throw)) => {
+            throw.push(&mut ManuallyDrop::new(Context::from_raw(raw)));
+            duktape_sys::duk_throw_raw(raw);
+            unreachable!()
+        }
+        Err(payload) => payload,
+    };
+    if payload.is::<FatalError>() {
+        // the heap is unusable, keep unwinding up to the Rust caller
+        std::panic::resume_unwind(payload);
+    }
+    throw_panic(raw, payload)
+}
+
+unsafe fn throw_panic(raw: *mut duktape_sys::duk_context, payload: Box<dyn Any + Send>) -> ! {
+    let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.as_str()
+    } else {
+        "native function panicked"
+    };
+    let _ = duktape_sys::duk_push_lstring(raw, msg.as_ptr() as *const i8, msg.len() as u64);
+    drop(payload);
+    push_error(raw, duktape_sys::DUK_ERR_ERROR);
+    // nothing on this frame needs dropping, the longjmp skips it
+    duktape_sys::duk_throw_raw(raw);
+    unreachable!()
+}
+
+// Replace the message on top of the stack with an error object.
+unsafe fn push_error(raw: *mut duktape_sys::duk_context, code: u32) {
+    duktape_sys::duk_push_error_object_raw(
+        raw,
+        code as i32,
+        std::ptr::null(),
+        0,
+        c"%s".as_ptr(),
+        duktape_sys::duk_get_string(raw, -1),
+    );
+    duktape_sys::duk_remove(raw, -2);
+}
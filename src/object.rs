@@ -0,0 +1,256 @@
+//! Typed access to properties of JS objects.
+
+use crate::value::{PeekError, PeekValue, PushValue};
+use crate::{Context, Error, JsRef};
+
+/// A JS object kept alive by a [`JsRef`].
+///
+/// Every operation leaves the value stack as it found it. Errors thrown by
+/// getters, setters or proxies are returned as [`Error::Js`].
+///
+/// ```
+///     use duktape::{Context, JsObject};
+///
+///     let mut ctx = Context::default();
+///     let obj = JsObject::new(&mut ctx);
+///     obj.set(&mut ctx, "answer", 42u32).unwrap();
+///     assert_eq!(obj.get::<u32>(&mut ctx, "answer").unwrap(), 42);
+///     assert!(obj.delete(&mut ctx, "answer").unwrap());
+///     assert!(!obj.has(&mut ctx, "answer").unwrap());
+/// ```
+#[derive(Clone)]
+pub struct JsObject {
+    object: JsRef,
+}
+
+#[derive(Clone, Copy)]
+enum Key<'a> {
+    Name(&'a str),
+    Index(u32),
+}
+
+impl Key<'_> {
+    fn push(self, ctx: &mut Context) {
+        match self {
+            Key::Name(name) => ctx.push_string(name),
+            Key::Index(index) => ctx.push_uint(index),
+        }
+    }
+}
+
+impl JsObject {
+    /// Create an empty object.
+    pub fn new(ctx: &mut Context) -> Self {
+        ctx.push_object();
+        JsObject {
+            object: JsRef::from_top(ctx),
+        }
+    }
+
+    /// The global object.
+    pub fn global(ctx: &mut Context) -> Self {
+        unsafe { duktape_sys::duk_push_global_object(ctx.inner) };
+        JsObject {
+            object: JsRef::from_top(ctx),
+        }
+    }
+
+    pub fn get<T: PeekValue>(&self, ctx: &mut Context, key: &str) -> Result<T, Error> {
+        self.get_key(ctx, Key::Name(key))
+    }
+
+    pub fn get_index<T: PeekValue>(&self, ctx: &mut Context, index: u32) -> Result<T, Error> {
+        self.get_key(ctx, Key::Index(index))
+    }
+
+    pub fn set<T: PushValue>(&self, ctx: &mut Context, key: &str, value: T) -> Result<(), Error> {
+        self.set_key(ctx, Key::Name(key), value)
+    }
+
+    pub fn set_index<T: PushValue>(
+        &self,
+        ctx: &mut Context,
+        index: u32,
+        value: T,
+    ) -> Result<(), Error> {
+        self.set_key(ctx, Key::Index(index), value)
+    }
+
+    /// Whether the object or its prototype chain has the property, like
+    /// the `in` operator.
+    pub fn has(&self, ctx: &mut Context, key: &str) -> Result<bool, Error> {
+        self.has_key(ctx, Key::Name(key))
+    }
+
+    pub fn has_index(&self, ctx: &mut Context, index: u32) -> Result<bool, Error> {
+        self.has_key(ctx, Key::Index(index))
+    }
+
+    /// Delete an own property, returns whether it existed.
+    pub fn delete(&self, ctx: &mut Context, key: &str) -> Result<bool, Error> {
+        self.delete_key(ctx, Key::Name(key))
+    }
+
+    pub fn delete_index(&self, ctx: &mut Context, index: u32) -> Result<bool, Error> {
+        self.delete_key(ctx, Key::Index(index))
+    }
+
+    /// Own enumerable property names, like `Object.keys`.
+    pub fn keys(&self, ctx: &mut Context) -> Result<Vec<String>, Error> {
+        use duktape_sys::{duk_enum, duk_next, duk_pop_2};
+
+        ctx.push(&self.object);
+        ctx.safe_call(1, |ctx| unsafe {
+            duk_enum(ctx.inner, -1, duktape_sys::DUK_ENUM_OWN_PROPERTIES_ONLY);
+            let mut keys = Vec::new();
+            while duk_next(ctx.inner, -1, 0) != 0 {
+                keys.push(ctx.get_string(-1));
+                ctx.pop_it();
+            }
+            duk_pop_2(ctx.inner);
+            keys
+        })
+    }
+
+    fn get_key<T: PeekValue>(&self, ctx: &mut Context, key: Key) -> Result<T, Error> {
+        ctx.push(&self.object);
+        key.push(ctx);
+        let value = ctx.safe_call(2, |ctx| {
+            unsafe { duktape_sys::duk_get_prop(ctx.inner, -2) };
+            JsRef::from_top(ctx)
+        })?;
+        // type checks throw too, but they are peek errors rather than
+        // errors of the script
+        ctx.push(&value);
+        ctx.peek_protected()
+            .map_err(|err| Error::Peek(PeekError::Thrown(Box::new(err))))?
+            .map_err(Error::Peek)
+    }
+
+    fn set_key<T: PushValue>(&self, ctx: &mut Context, key: Key, value: T) -> Result<(), Error> {
+        ctx.push(&self.object);
+        key.push(ctx);
+        ctx.push(value);
+        ctx.safe_call(3, |ctx| unsafe {
+            duktape_sys::duk_put_prop(ctx.inner, -3);
+            ctx.pop_it();
+        })
+    }
+
+    fn has_key(&self, ctx: &mut Context, key: Key) -> Result<bool, Error> {
+        ctx.push(&self.object);
+        key.push(ctx);
+        ctx.safe_call(2, |ctx| unsafe {
+            let has = duktape_sys::duk_has_prop(ctx.inner, -2);
+            ctx.pop_it();
+            has != 0
+        })
+    }
+
+    fn delete_key(&self, ctx: &mut Context, key: Key) -> Result<bool, Error> {
+        ctx.push(&self.object);
+        key.push(ctx);
+        // deleting a missing property succeeds too, check it existed first
+        ctx.safe_call(2, |ctx| unsafe {
+            duktape_sys::duk_dup(ctx.inner, -1);
+            duktape_sys::duk_get_prop_desc(ctx.inner, -3, 0);
+            let existed = duktape_sys::duk_is_object(ctx.inner, -1) != 0;
+            ctx.pop_it();
+            duktape_sys::duk_del_prop(ctx.inner, -2);
+            ctx.pop_it();
+            existed
+        })
+    }
+}
+
+impl AsRef<JsRef> for JsObject {
+    fn as_ref(&self) -> &JsRef {
+        &self.object
+    }
+}
+
+impl PushValue for &JsObject {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        ctx.push(&self.object)
+    }
+}
+
+impl PeekValue for JsObject {
+    fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
+        if unsafe { duktape_sys::duk_is_object(ctx.inner, idx) } == 0 {
+            return Err(PeekError::Type("object"));
+        }
+        Ok(JsObject {
+            object: JsRef::new(ctx, idx),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties() {
+        let mut ctx = Context::default();
+        let obj: JsObject = ctx.eval("({a: 1, b: 'two', list: [1, 2]})").unwrap();
+        ctx.pop_it();
+
+        assert_eq!(obj.get::<u32>(&mut ctx, "a").unwrap(), 1);
+        assert_eq!(obj.get::<String>(&mut ctx, "b").unwrap(), "two");
+        assert_eq!(obj.get::<Option<u32>>(&mut ctx, "c").unwrap(), None);
+        assert!(matches!(
+            obj.get::<JsObject>(&mut ctx, "a"),
+            Err(Error::Peek(PeekError::Type("object")))
+        ));
+        assert!(matches!(
+            obj.get::<u32>(&mut ctx, "list"),
+            Err(Error::Peek(PeekError::Thrown(_)))
+        ));
+
+        let list: JsObject = obj.get(&mut ctx, "list").unwrap();
+        list.set_index(&mut ctx, 2, 3u32).unwrap();
+        assert!(list.has_index(&mut ctx, 2).unwrap());
+        assert_eq!(list.get_index::<u32>(&mut ctx, 2).unwrap(), 3);
+        assert!(list.delete_index(&mut ctx, 0).unwrap());
+        assert!(!list.delete_index(&mut ctx, 0).unwrap());
+
+        obj.set(&mut ctx, "c", "three".to_string()).unwrap();
+        assert_eq!(obj.keys(&mut ctx).unwrap(), ["a", "b", "list", "c"]);
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn thrown_errors() {
+        let mut ctx = Context::default();
+        let obj: JsObject = ctx
+            .eval(
+                "Object.defineProperty({}, 'x', {
+                    get: function() { throw new Error('no x') },
+                    configurable: false,
+                })",
+            )
+            .unwrap();
+        ctx.pop_it();
+
+        match obj.get::<u32>(&mut ctx, "x") {
+            Err(Error::Js(e)) => assert_eq!(e.message, "no x"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(obj.delete(&mut ctx, "x"), Err(Error::Js(_))));
+        assert!(matches!(obj.set(&mut ctx, "x", 1u32), Err(Error::Js(_))));
+        assert!(matches!(
+            ctx.peek::<JsObject>(-1),
+            Err(PeekError::Type("object"))
+        ));
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn global() {
+        let mut ctx = Context::default();
+        let global = JsObject::global(&mut ctx);
+        global.set(&mut ctx, "answer", 42u32).unwrap();
+        assert_eq!(ctx.eval::<u32>("answer").unwrap(), 42);
+    }
+}
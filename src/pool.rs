@@ -0,0 +1,460 @@
+//! A pool of initialized contexts shared between threads.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::{ContextBuilder, Error, SendContext};
+
+type Init = Box<dyn Fn(&mut SendContext) -> Result<(), Error> + Send + Sync>;
+
+/// When a returned context is handed out again, see
+/// [`ContextPoolBuilder::reuse`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reuse {
+    /// Keep every context, with whatever state its users left behind.
+    Always,
+    /// Replace contexts in which an error was returned to the host, which
+    /// may have left globals half updated, or which still have timers or
+    /// promise jobs pending that would run for the next user.
+    #[default]
+    UnlessFailed,
+    /// Replace every context, each checkout gets one fresh from the init
+    /// closure.
+    Never,
+}
+
+/// Configures and creates a [`ContextPool`].
+pub struct ContextPoolBuilder {
+    size: usize,
+    memory_limit: Option<usize>,
+    memory_threshold: Option<usize>,
+    reuse: Reuse,
+    init: Init,
+}
+
+impl ContextPoolBuilder {
+    /// Number of contexts kept by the pool, 4 by default.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Limit the number of bytes each heap may allocate, see
+    /// [`ContextBuilder::memory_limit`].
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Discard contexts using more than `bytes` after a garbage collection
+    /// when they are returned.
+    pub fn memory_threshold(mut self, bytes: usize) -> Self {
+        self.memory_threshold = Some(bytes);
+        self
+    }
+
+    /// Which returned contexts are handed out again,
+    /// [`Reuse::UnlessFailed`] by default.
+    pub fn reuse(mut self, reuse: Reuse) -> Self {
+        self.reuse = reuse;
+        self
+    }
+
+    /// Create the pool and its contexts.
+    pub fn build(self) -> Result<ContextPool, Error> {
+        let pool = ContextPool {
+            state: Mutex::new(State {
+                idle: Vec::with_capacity(self.size),
+                metrics: PoolMetrics {
+                    size: self.size,
+                    ..Default::default()
+                },
+            }),
+            returned: Condvar::new(),
+            memory_limit: self.memory_limit,
+            memory_threshold: self.memory_threshold,
+            reuse: self.reuse,
+            init: self.init,
+        };
+        for _ in 0..self.size {
+            let ctx = pool.create()?;
+            let mut state = pool.lock();
+            state.idle.push(ctx);
+            state.metrics.created += 1;
+        }
+        Ok(pool)
+    }
+}
+
+/// Counters describing the use of a [`ContextPool`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Number of contexts the pool keeps.
+    pub size: usize,
+    /// Contexts waiting to be handed out.
+    pub idle: usize,
+    /// Contexts currently handed out.
+    pub in_use: usize,
+    /// Contexts created, including replacements for discarded ones.
+    pub created: u64,
+    /// Contexts discarded because they were poisoned, used too much memory,
+    /// failed or were discarded explicitly.
+    pub discarded: u64,
+    /// Contexts handed out.
+    pub checkouts: u64,
+    /// Checkouts which had to wait for a context to be returned.
+    pub waits: u64,
+}
+
+struct State {
+    idle: Vec<SendContext>,
+    metrics: PoolMetrics,
+}
+
+/// Contexts created by an init closure, handed out one at a time.
+///
+/// Every context is set up once by the init closure, which typically
+/// registers host functions and runs bootstrap scripts. Contexts which ran
+/// into a fatal error, used too much memory, or were
+/// [discarded](PooledContext::discard) are replaced by fresh ones.
+///
+/// **Returned contexts are not isolated from the next user.** Only the
+/// value stack is cleared, globals, registered closures and anything else
+/// a script changed stay as the previous user left them. By default
+/// contexts are only replaced when a checkout ended with an error or left
+/// timers or promise jobs pending, use [`Reuse::Never`] if every checkout
+/// needs a context nobody used before.
+///
+/// ```
+///     use duktape::ContextPool;
+///
+///     let pool = ContextPool::builder(|ctx| {
+///         ctx.with(|ctx| {
+///             ctx.eval::<()>("function handle(req) { return 'hello ' + req }")
+///         })
+///     })
+///     .size(2)
+///     .build()
+///     .unwrap();
+///
+///     std::thread::scope(|s| {
+///         for _ in 0..4 {
+///             s.spawn(|| {
+///                 let mut ctx = pool.get().unwrap();
+///                 let res: String = ctx.with(|ctx| ctx.eval("handle('bob')").unwrap());
+///                 assert_eq!(res, "hello bob");
+///             });
+///         }
+///     });
+///     assert_eq!(pool.metrics().checkouts, 4);
+/// ```
+pub struct ContextPool {
+    state: Mutex<State>,
+    returned: Condvar,
+    memory_limit: Option<usize>,
+    memory_threshold: Option<usize>,
+    reuse: Reuse,
+    init: Init,
+}
+
+impl ContextPool {
+    /// Configure a pool whose contexts are set up by `init`.
+    pub fn builder<F>(init: F) -> ContextPoolBuilder
+    where
+        F: Fn(&mut SendContext) -> Result<(), Error> + Send + Sync + 'static,
+    {
+        ContextPoolBuilder {
+            size: 4,
+            memory_limit: None,
+            memory_threshold: None,
+            reuse: Reuse::default(),
+            init: Box::new(init),
+        }
+    }
+
+    /// Take a context, waiting for one to be returned if all are in use.
+    ///
+    /// Fails if a discarded context had to be replaced and the init closure
+    /// failed.
+    pub fn get(&self) -> Result<PooledContext<'_>, Error> {
+        let mut state = self.lock();
+        let mut waited = false;
+        loop {
+            if let Some(ctx) = state.idle.pop() {
+                state.metrics.checkouts += 1;
+                state.metrics.waits += waited as u64;
+                state.metrics.in_use += 1;
+                return Ok(PooledContext {
+                    pool: self,
+                    ctx: Some(ctx),
+                    discard: false,
+                });
+            }
+            if state.metrics.in_use < state.metrics.size {
+                // a discarded context left a gap, fill it
+                state.metrics.in_use += 1;
+                drop(state);
+                let ctx = self.create();
+                state = self.lock();
+                state.metrics.in_use -= 1;
+                let ctx = ctx?;
+                state.metrics.created += 1;
+                state.idle.push(ctx);
+                continue;
+            }
+            waited = true;
+            state = self.returned.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.lock();
+        PoolMetrics {
+            idle: state.idle.len(),
+            ..state.metrics.clone()
+        }
+    }
+
+    fn create(&self) -> Result<SendContext, Error> {
+        let mut builder = ContextBuilder::new();
+        if let Some(limit) = self.memory_limit {
+            builder = builder.memory_limit(limit);
+        }
+        // a context without a fatal handler owns nothing tied to a thread
+        let mut ctx = SendContext::new(builder.build()?)
+            .unwrap_or_else(|_| unreachable!("new context isn't sendable"));
+        (self.init)(&mut ctx)?;
+        ctx.with(|ctx| {
+            ctx.pop_n(ctx.stack_len());
+            // errors handled by the init closure don't count
+            ctx.heap().errors.set(0);
+        });
+        Ok(ctx)
+    }
+
+    fn give_back(&self, mut ctx: SendContext, discard: bool) {
+        let keep = !discard && self.reset(&mut ctx);
+        let mut state = self.lock();
+        state.metrics.in_use -= 1;
+        if keep {
+            state.idle.push(ctx);
+        } else {
+            state.metrics.discarded += 1;
+        }
+        drop(state);
+        self.returned.notify_one();
+    }
+
+    // Prepare a context for the next user, returns whether it's reusable.
+    fn reset(&self, ctx: &mut SendContext) -> bool {
+        let threshold = self.memory_threshold;
+        let reuse = self.reuse;
+        ctx.with(|ctx| {
+            if ctx.is_poisoned() {
+                return false;
+            }
+            unsafe { duktape_sys::duk_set_top(ctx.inner, 0) };
+            let failed = ctx.heap().errors.replace(0) > 0
+                || ctx.pending_timers() > 0
+                || ctx.pending_jobs().map_or(true, |n| n > 0);
+            match reuse {
+                Reuse::Always => (),
+                Reuse::UnlessFailed if !failed => (),
+                _ => return false,
+            }
+            match threshold {
+                Some(threshold) if ctx.memory_used() > threshold => {
+                    unsafe { duktape_sys::duk_gc(ctx.inner, 0) };
+                    ctx.memory_used() <= threshold
+                }
+                _ => true,
+            }
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // the state is consistent between statements, a panic can't leave
+        // it half updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A context taken from a [`ContextPool`], returned to it when dropped.
+pub struct PooledContext<'a> {
+    pool: &'a ContextPool,
+    ctx: Option<SendContext>,
+    discard: bool,
+}
+
+impl PooledContext<'_> {
+    /// Don't return the context to the pool, it's replaced by a new one.
+    pub fn discard(mut self) {
+        self.discard = true;
+    }
+}
+
+impl Deref for PooledContext<'_> {
+    type Target = SendContext;
+
+    fn deref(&self) -> &SendContext {
+        self.ctx.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledContext<'_> {
+    fn deref_mut(&mut self) -> &mut SendContext {
+        self.ctx.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledContext<'_> {
+    fn drop(&mut self) {
+        if let Some(ctx) = self.ctx.take() {
+            self.pool.give_back(ctx, self.discard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JsObject;
+
+    #[test]
+    fn reuse_and_replace() {
+        let pool = ContextPool::builder(|ctx| {
+            ctx.with(|ctx| ctx.eval::<()>("var uses = 0"))?;
+            ctx.register_closure("ping", |_ctx, _args| "pong".to_string());
+            Ok(())
+        })
+        .size(1)
+        .build()
+        .unwrap();
+
+        for uses in 1..=2 {
+            let mut ctx = pool.get().unwrap();
+            let n: u32 = ctx.with(|ctx| ctx.eval("++uses").unwrap());
+            assert_eq!(n, uses);
+        }
+        let mut ctx = pool.get().unwrap();
+        assert_eq!(ctx.with(|ctx| ctx.stack_len()), 0);
+        ctx.discard();
+
+        let mut ctx = pool.get().unwrap();
+        let res: (u32, String) = ctx.with(|ctx| {
+            let n = ctx.eval("++uses").unwrap();
+            (n, ctx.eval("ping()").unwrap())
+        });
+        assert_eq!(res, (1, "pong".to_string()));
+        drop(ctx);
+
+        assert_eq!(
+            pool.metrics(),
+            PoolMetrics {
+                size: 1,
+                idle: 1,
+                in_use: 0,
+                created: 2,
+                discarded: 1,
+                checkouts: 4,
+                waits: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn memory_threshold() {
+        let pool = ContextPool::builder(|_ctx| Ok(()))
+            .size(1)
+            .memory_threshold(512 * 1024)
+            .build()
+            .unwrap();
+        let mut ctx = pool.get().unwrap();
+        ctx.with(|ctx| {
+            ctx.eval::<()>("var garbage = []; for (var i = 0; i < 1e4; i++) garbage.push('x' + i)")
+                .unwrap()
+        });
+        drop(ctx);
+        assert_eq!(pool.metrics().discarded, 1);
+
+        let mut ctx = pool.get().unwrap();
+        ctx.with(|ctx| {
+            ctx.eval::<()>("var garbage = []; for (var i = 0; i < 1e4; i++) garbage.push('x' + i); garbage = null")
+                .unwrap()
+        });
+        drop(ctx);
+        assert_eq!(pool.metrics().discarded, 1);
+    }
+
+    #[test]
+    fn replace_failed() {
+        let pool =
+            ContextPool::builder(|ctx| ctx.with(|ctx| ctx.eval::<()>("var state = 'clean'")))
+                .size(1)
+                .build()
+                .unwrap();
+
+        // a script failing half way leaves the context behind dirty
+        let mut ctx = pool.get().unwrap();
+        let res = ctx.with(|ctx| ctx.eval::<()>("state = 'dirty'; null.crash"));
+        assert!(res.is_err());
+        drop(ctx);
+        let mut ctx = pool.get().unwrap();
+        let state: String = ctx.with(|ctx| ctx.eval("state").unwrap());
+        assert_eq!(state, "clean");
+        drop(ctx);
+        assert_eq!(pool.metrics().discarded, 1);
+
+        // so do promise jobs left for the next user
+        let mut ctx = pool.get().unwrap();
+        ctx.with(|ctx| {
+            ctx.install_promise().unwrap();
+            ctx.eval::<()>("Promise.resolve(1).then(function () { state = 'dirty' })")
+                .unwrap();
+        });
+        drop(ctx);
+        assert_eq!(pool.metrics().discarded, 2);
+
+        let pool = ContextPool::builder(|_ctx| Ok(()))
+            .size(1)
+            .reuse(Reuse::Never)
+            .build()
+            .unwrap();
+        let mut ctx = pool.get().unwrap();
+        ctx.with(|ctx| ctx.eval::<()>("var leaked = true").unwrap());
+        drop(ctx);
+        let mut ctx = pool.get().unwrap();
+        assert_eq!(
+            ctx.with(|ctx| ctx.eval::<String>("typeof leaked").unwrap()),
+            "undefined"
+        );
+    }
+
+    #[test]
+    fn keep_after_handled_errors() {
+        let pool = ContextPool::builder(|ctx| {
+            ctx.register_closure("count", |ctx, args| args.get::<u32>(ctx, 0).unwrap_or(0));
+            Ok(())
+        })
+        .size(1)
+        .build()
+        .unwrap();
+
+        // failed type checks handled by the host don't make a context dirty
+        let mut ctx = pool.get().unwrap();
+        ctx.with(|ctx| {
+            assert_eq!(ctx.eval::<u32>("count('x')").unwrap(), 0);
+            let obj: JsObject = ctx.eval("({a: 'x'})").unwrap();
+            assert!(obj.get::<u32>(ctx, "a").is_err());
+        });
+        drop(ctx);
+        assert_eq!(pool.metrics().discarded, 0);
+    }
+
+    #[test]
+    fn failing_init() {
+        let res =
+            ContextPool::builder(|ctx| ctx.with(|ctx| ctx.eval::<()>("syntax error"))).build();
+        assert!(matches!(res, Err(Error::Js(_))));
+    }
+}
@@ -0,0 +1,553 @@
+//! `Promise` with a job queue drained by the host.
+//!
+//! Duktape doesn't implement promises, [`Context::install_promise`] adds
+//! a `Promise` global written in ES5. Reactions are queued as jobs, which
+//! only run when the host calls [`Context::run_jobs`]. Iterables aren't
+//! supported by the engine, so the combinators accept arrays.
+
+use crate::value::{JsValue, PeekError, PeekValue, PushValue};
+use crate::{CompileOptions, Context, Error, JsFunction, JsObject};
+
+const HELPER_PROP: &[u8] = b"\xffpromise";
+
+// per promise state, a hidden symbol scripts can't reach
+struct RecordKey;
+
+impl PushValue for RecordKey {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        let key = b"\xffpromise";
+        unsafe {
+            duktape_sys::duk_push_lstring(ctx.inner, key.as_ptr() as *const i8, key.len() as u64)
+        };
+        ctx.stack_top()
+    }
+}
+
+const PROMISE: &str = "(function (KEY) {
+    'use strict';
+    var PENDING = 0, FULFILLED = 1, REJECTED = 2;
+    var jobs = [], head = 0;
+
+    function enqueue(job) {
+        jobs.push(job);
+    }
+
+    function take() {
+        if (head === jobs.length) {
+            return undefined;
+        }
+        var job = jobs[head];
+        jobs[head++] = undefined;
+        if (head === jobs.length) {
+            jobs.length = 0;
+            head = 0;
+        }
+        return job;
+    }
+
+    function isCallable(f) {
+        return typeof f === 'function';
+    }
+
+    function record(p) {
+        var rec = p !== null && typeof p === 'object' ? p[KEY] : undefined;
+        if (!rec) {
+            throw new TypeError('not a promise');
+        }
+        return rec;
+    }
+
+    function schedule(reaction, state, value) {
+        enqueue(function () {
+            var handler = state === FULFILLED ? reaction.onFulfilled : reaction.onRejected;
+            var cap = reaction.capability;
+            if (!isCallable(handler)) {
+                if (state === FULFILLED) {
+                    cap.resolve(value);
+                } else {
+                    cap.reject(value);
+                }
+                return;
+            }
+            var result;
+            try {
+                result = handler(value);
+            } catch (e) {
+                cap.reject(e);
+                return;
+            }
+            cap.resolve(result);
+        });
+    }
+
+    function settle(p, state, value) {
+        var rec = p[KEY];
+        var reactions = rec.reactions;
+        rec.state = state;
+        rec.value = value;
+        rec.reactions = undefined;
+        for (var i = 0; i < reactions.length; i++) {
+            schedule(reactions[i], state, value);
+        }
+    }
+
+    function resolvePromise(p, value) {
+        if (value === p) {
+            settle(p, REJECTED, new TypeError('promise resolved with itself'));
+            return;
+        }
+        if (value !== null && (typeof value === 'object' || typeof value === 'function')) {
+            var then;
+            try {
+                then = value.then;
+            } catch (e) {
+                settle(p, REJECTED, e);
+                return;
+            }
+            if (isCallable(then)) {
+                enqueue(function () {
+                    var r = resolvingFunctions(p);
+                    try {
+                        then.call(value, r.resolve, r.reject);
+                    } catch (e) {
+                        r.reject(e);
+                    }
+                });
+                return;
+            }
+        }
+        settle(p, FULFILLED, value);
+    }
+
+    function resolvingFunctions(p) {
+        var done = false;
+        return {
+            resolve: function (value) {
+                if (!done) {
+                    done = true;
+                    resolvePromise(p, value);
+                }
+            },
+            reject: function (reason) {
+                if (!done) {
+                    done = true;
+                    settle(p, REJECTED, reason);
+                }
+            }
+        };
+    }
+
+    function Promise(executor) {
+        if (!(this instanceof Promise)) {
+            throw new TypeError('Promise must be called with new');
+        }
+        if (!isCallable(executor)) {
+            throw new TypeError('Promise executor is not a function');
+        }
+        this[KEY] = { state: PENDING, value: undefined, reactions: [] };
+        var r = resolvingFunctions(this);
+        try {
+            executor(r.resolve, r.reject);
+        } catch (e) {
+            r.reject(e);
+        }
+    }
+
+    function capability() {
+        var cap = {};
+        cap.promise = new Promise(function (resolve, reject) {
+            cap.resolve = resolve;
+            cap.reject = reject;
+        });
+        return cap;
+    }
+
+    Promise.prototype.then = function (onFulfilled, onRejected) {
+        var rec = record(this);
+        var cap = capability();
+        var reaction = { capability: cap, onFulfilled: onFulfilled, onRejected: onRejected };
+        if (rec.state === PENDING) {
+            rec.reactions.push(reaction);
+        } else {
+            schedule(reaction, rec.state, rec.value);
+        }
+        return cap.promise;
+    };
+
+    Promise.prototype['catch'] = function (onRejected) {
+        return this.then(undefined, onRejected);
+    };
+
+    Promise.prototype['finally'] = function (onFinally) {
+        if (!isCallable(onFinally)) {
+            return this.then(onFinally, onFinally);
+        }
+        return this.then(function (value) {
+            return Promise.resolve(onFinally()).then(function () {
+                return value;
+            });
+        }, function (reason) {
+            return Promise.resolve(onFinally()).then(function () {
+                throw reason;
+            });
+        });
+    };
+
+    Promise.resolve = function (value) {
+        if (value instanceof Promise) {
+            return value;
+        }
+        var cap = capability();
+        cap.resolve(value);
+        return cap.promise;
+    };
+
+    Promise.reject = function (reason) {
+        var cap = capability();
+        cap.reject(reason);
+        return cap.promise;
+    };
+
+    // settle with `done(values)` once every item has called `settled`
+    function combine(items, cap, each, done) {
+        try {
+            var values = [], remaining = 1;
+            var settled = function (i, value) {
+                values[i] = value;
+                if (--remaining === 0) {
+                    done(values);
+                }
+            };
+            for (var i = 0; i < items.length; i++) {
+                remaining++;
+                values[i] = undefined;
+                each(Promise.resolve(items[i]), i, settled);
+            }
+            if (--remaining === 0) {
+                done(values);
+            }
+        } catch (e) {
+            cap.reject(e);
+        }
+        return cap.promise;
+    }
+
+    Promise.all = function (items) {
+        var cap = capability();
+        return combine(items, cap, function (p, i, settled) {
+            p.then(function (value) {
+                settled(i, value);
+            }, cap.reject);
+        }, cap.resolve);
+    };
+
+    Promise.allSettled = function (items) {
+        var cap = capability();
+        return combine(items, cap, function (p, i, settled) {
+            p.then(function (value) {
+                settled(i, { status: 'fulfilled', value: value });
+            }, function (reason) {
+                settled(i, { status: 'rejected', reason: reason });
+            });
+        }, cap.resolve);
+    };
+
+    Promise.race = function (items) {
+        var cap = capability();
+        try {
+            for (var i = 0; i < items.length; i++) {
+                Promise.resolve(items[i]).then(cap.resolve, cap.reject);
+            }
+        } catch (e) {
+            cap.reject(e);
+        }
+        return cap.promise;
+    };
+
+    return {
+        Promise: Promise,
+        capability: capability,
+        take: take,
+        pending: function () {
+            return jobs.length - head;
+        },
+        state: function (p) {
+            var rec = record(p);
+            return [rec.state, rec.value];
+        }
+    };
+})";
+
+impl Context {
+    /// Add the `Promise` global.
+    ///
+    /// ```
+    ///     use duktape::Context;
+    ///
+    ///     let mut ctx = Context::default();
+    ///     ctx.install_promise().unwrap();
+    ///     ctx.eval::<()>("var out; Promise.resolve(1).then(function (n) { out = n + 1 })")
+    ///         .unwrap();
+    ///     ctx.pop().unwrap();
+    ///     assert_eq!(ctx.run_jobs().unwrap(), 1);
+    ///     assert_eq!(ctx.eval::<u32>("out").unwrap(), 2);
+    /// ```
+    pub fn install_promise(&mut self) -> Result<(), Error> {
+        let script = self.compile(PROMISE, CompileOptions::default())?;
+        let init: JsFunction = script.run(self)?;
+        self.pop_it();
+        let helper: JsObject = init.call(self, (RecordKey,))?;
+        let promise: JsFunction = helper.get(self, "Promise")?;
+        JsObject::global(self).set(self, "Promise", &promise)?;
+
+        self.push(&helper);
+        self.put_stashed(HELPER_PROP);
+        Ok(())
+    }
+
+    /// Run queued promise jobs, including jobs queued while running them,
+    /// returns how many ran.
+    ///
+    /// Jobs handle errors thrown by promise callbacks themselves, an error
+    /// is only returned if the engine failed to run one, for example
+    /// because it ran out of memory. Later jobs stay queued.
+    pub fn run_jobs(&mut self) -> Result<usize, Error> {
+        let helper = match promise_helper(self)? {
+            Some(helper) => helper,
+            None => return Ok(0),
+        };
+        let take: JsFunction = helper.get(self, "take")?;
+        let mut n = 0;
+        while let Some(job) = take.call::<_, Option<JsFunction>>(self, ())? {
+            job.call::<_, ()>(self, ())?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Number of queued promise jobs.
+    pub(crate) fn pending_jobs(&mut self) -> Result<usize, Error> {
+        let helper = match promise_helper(self)? {
+            Some(helper) => helper,
+            None => return Ok(0),
+        };
+        let pending: JsFunction = helper.get(self, "pending")?;
+        pending.call::<_, u32>(self, ()).map(|n| n as usize)
+    }
+}
+
+fn promise_helper(ctx: &mut Context) -> Result<Option<JsObject>, Error> {
+    ctx.get_stashed(HELPER_PROP)
+}
+
+/// State of a promise, see [`JsPromise::state`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromiseState<T> {
+    Pending,
+    Fulfilled(T),
+    /// The reason can be any value, usually an `Error`.
+    Rejected(JsValue),
+}
+
+/// A promise created by scripts or by the host.
+///
+/// ```
+///     use duktape::{Context, JsPromise};
+///
+///     let mut ctx = Context::default();
+///     ctx.install_promise().unwrap();
+///     let (promise, resolver) = JsPromise::new(&mut ctx).unwrap();
+///     ctx.push(&promise);
+///     ctx.put_global_string("ready");
+///     ctx.eval::<()>("var out; ready.then(function (s) { out = s + '!' })")
+///         .unwrap();
+///     ctx.pop().unwrap();
+///
+///     resolver.resolve(&mut ctx, "done".to_string()).unwrap();
+///     ctx.run_jobs().unwrap();
+///     assert_eq!(ctx.eval::<String>("out").unwrap(), "done!");
+/// ```
+#[derive(Clone)]
+pub struct JsPromise {
+    promise: JsObject,
+}
+
+/// Settles a promise created by [`JsPromise::new`], only the first call
+/// has an effect.
+#[derive(Clone)]
+pub struct Resolver {
+    resolve: JsFunction,
+    reject: JsFunction,
+}
+
+impl JsPromise {
+    /// Create a pending promise settled through the returned [`Resolver`].
+    ///
+    /// Fails if the `Promise` global wasn't installed.
+    pub fn new(ctx: &mut Context) -> Result<(JsPromise, Resolver), Error> {
+        let helper = promise_helper(ctx)?
+            .ok_or_else(|| Error::Message("Promise isn't installed".to_string()))?;
+        let capability: JsFunction = helper.get(ctx, "capability")?;
+        let cap: JsObject = capability.call(ctx, ())?;
+        let promise = JsPromise {
+            promise: cap.get(ctx, "promise")?,
+        };
+        let resolver = Resolver {
+            resolve: cap.get(ctx, "resolve")?,
+            reject: cap.get(ctx, "reject")?,
+        };
+        Ok((promise, resolver))
+    }
+
+    /// Get the state and, once settled, the value of the promise.
+    pub fn state<T: PeekValue>(&self, ctx: &mut Context) -> Result<PromiseState<T>, Error> {
+        let helper = promise_helper(ctx)?
+            .ok_or_else(|| Error::Message("Promise isn't installed".to_string()))?;
+        let state: JsFunction = helper.get(ctx, "state")?;
+        let record: JsObject = state.call(ctx, (&self.promise,))?;
+        Ok(match record.get_index::<u32>(ctx, 0)? {
+            0 => PromiseState::Pending,
+            1 => PromiseState::Fulfilled(record.get_index(ctx, 1)?),
+            _ => PromiseState::Rejected(record.get_index(ctx, 1)?),
+        })
+    }
+}
+
+impl Resolver {
+    /// Resolve the promise with `value`, which may be another promise.
+    pub fn resolve<T: PushValue>(&self, ctx: &mut Context, value: T) -> Result<(), Error> {
+        self.resolve.call(ctx, (value,))
+    }
+
+    pub fn reject<T: PushValue>(&self, ctx: &mut Context, reason: T) -> Result<(), Error> {
+        self.reject.call(ctx, (reason,))
+    }
+}
+
+impl AsRef<JsObject> for JsPromise {
+    fn as_ref(&self) -> &JsObject {
+        &self.promise
+    }
+}
+
+impl PushValue for &JsPromise {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        ctx.push(&self.promise)
+    }
+}
+
+/// Any object can be peeked, [`JsPromise::state`] fails for objects which
+/// aren't promises.
+impl PeekValue for JsPromise {
+    fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
+        Ok(JsPromise {
+            promise: JsObject::peek_at(ctx, idx)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::eval;
+
+    fn context() -> Context {
+        let mut ctx = Context::default();
+        ctx.install_promise().unwrap();
+        ctx
+    }
+
+    #[test]
+    fn chaining() {
+        let mut ctx = context();
+        eval::<()>(
+            &mut ctx,
+            "var log = [];
+            new Promise(function (resolve) { resolve(1) })
+                .then(function (n) { log.push(n); throw new Error('boom') })
+                .then(function () { log.push('skipped') })
+                .catch(function (e) { log.push(e.message); return Promise.resolve(2) })
+                .finally(function () { log.push('finally') })
+                .then(function (n) { log.push(n) });
+            log.push('sync')",
+        );
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "sync");
+        ctx.run_jobs().unwrap();
+        assert_eq!(
+            eval::<String>(&mut ctx, "log.join()"),
+            "sync,1,boom,finally,2"
+        );
+        assert_eq!(ctx.run_jobs().unwrap(), 0);
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn combinators() {
+        let mut ctx = context();
+        let all: JsPromise = eval(&mut ctx, "Promise.all([1, Promise.resolve(2), 3])");
+        let failed: JsPromise = eval(&mut ctx, "Promise.all([1, Promise.reject('no')])");
+        let race: JsPromise = eval(
+            &mut ctx,
+            "Promise.race([new Promise(function () {}), Promise.resolve('first')])",
+        );
+        let settled: JsPromise = eval(&mut ctx, "Promise.allSettled([1, Promise.reject('no')])");
+        assert_eq!(
+            all.state::<Vec<u32>>(&mut ctx).unwrap(),
+            PromiseState::Pending
+        );
+        ctx.run_jobs().unwrap();
+
+        assert_eq!(
+            all.state::<Vec<u32>>(&mut ctx).unwrap(),
+            PromiseState::Fulfilled(vec![1, 2, 3])
+        );
+        assert_eq!(
+            failed.state::<()>(&mut ctx).unwrap(),
+            PromiseState::Rejected(JsValue::String("no".to_string()))
+        );
+        assert_eq!(
+            race.state::<String>(&mut ctx).unwrap(),
+            PromiseState::Fulfilled("first".to_string())
+        );
+        let statuses: Vec<JsValue> = match settled.state(&mut ctx).unwrap() {
+            PromiseState::Fulfilled(JsValue::Array(values)) => values,
+            state => panic!("unexpected state {:?}", state),
+        };
+        assert_eq!(statuses.len(), 2);
+    }
+
+    #[test]
+    fn host_promise() {
+        let mut ctx = context();
+        let (promise, resolver) = JsPromise::new(&mut ctx).unwrap();
+        let chained: JsFunction = eval(
+            &mut ctx,
+            "(function (p) { return p.then(null, function (e) { return 'caught ' + e }) })",
+        );
+        let chained: JsPromise = chained.call(&mut ctx, (&promise,)).unwrap();
+        resolver.reject(&mut ctx, "late".to_string()).unwrap();
+        resolver.resolve(&mut ctx, 1u32).unwrap();
+        assert_eq!(
+            promise.state::<u32>(&mut ctx).unwrap(),
+            PromiseState::Rejected(JsValue::String("late".to_string()))
+        );
+        ctx.run_jobs().unwrap();
+        assert_eq!(
+            chained.state::<String>(&mut ctx).unwrap(),
+            PromiseState::Fulfilled("caught late".to_string())
+        );
+
+        let not_promise: JsPromise = eval(&mut ctx, "({})");
+        assert!(matches!(
+            not_promise.state::<()>(&mut ctx),
+            Err(Error::Js(_))
+        ));
+    }
+
+    #[test]
+    fn not_installed() {
+        let mut ctx = Context::default();
+        assert_eq!(ctx.run_jobs().unwrap(), 0);
+        assert!(JsPromise::new(&mut ctx).is_err());
+    }
+}
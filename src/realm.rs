@@ -0,0 +1,190 @@
+//! Separate global environments sharing one heap.
+//!
+//! A realm is a Duktape thread created with a fresh set of built-ins and
+//! its own global object. Values can be passed between realms of the same
+//! heap, but functions keep resolving globals in the realm they were
+//! created in.
+
+use std::mem::ManuallyDrop;
+
+use crate::function::PushArgs;
+use crate::value::PeekValue;
+use crate::{Context, Error, JsFunction, JsObject, JsRef};
+
+/// A global environment created by [`Context::new_realm`].
+///
+/// ```
+///     use duktape::Context;
+///
+///     let mut ctx = Context::default();
+///     ctx.register_closure("double", |ctx, args| 2 * args.get::<u32>(ctx, 0).unwrap());
+///     ctx.eval::<()>("var tenant = 'host'").unwrap();
+///     ctx.pop().unwrap();
+///
+///     let realm = ctx.new_realm();
+///     realm.copy_globals(&mut ctx, &["double"]).unwrap();
+///     let n: u32 = realm.eval(&mut ctx, "double(21)").unwrap();
+///     assert_eq!(n, 42);
+///     assert!(realm.eval::<bool>(&mut ctx, "typeof tenant === 'undefined'").unwrap());
+/// ```
+#[derive(Clone)]
+pub struct Realm {
+    thread: JsRef,
+}
+
+impl Context {
+    /// Create a realm with its own global object and built-ins.
+    pub fn new_realm(&mut self) -> Realm {
+        unsafe {
+            duktape_sys::duk_push_thread_raw(self.inner, duktape_sys::DUK_THREAD_NEW_GLOBAL_ENV)
+        };
+        Realm {
+            thread: JsRef::from_top(self),
+        }
+    }
+}
+
+impl Realm {
+    /// Run `f` with a context whose globals are the realm's.
+    ///
+    /// The realm has a value stack of its own, values left on it stay
+    /// there until the next call pops them.
+    ///
+    /// Fails without running `f` once the context is poisoned.
+    pub fn with<F, R>(&self, ctx: &mut Context, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Context) -> R,
+    {
+        if ctx.is_poisoned() {
+            return Err(Error::Poisoned);
+        }
+        ctx.push(&self.thread);
+        let raw = unsafe { duktape_sys::duk_get_context(ctx.inner, -1) };
+        ctx.pop_it();
+        // the thread is kept alive by the stash, and shares the heap
+        let realm = &mut ManuallyDrop::new(unsafe { Context::from_raw(raw) });
+        Ok(f(realm))
+    }
+
+    /// Evaluate `source` against the realm's globals.
+    pub fn eval<T: PeekValue>(&self, ctx: &mut Context, source: &str) -> Result<T, Error> {
+        self.with(ctx, |realm| {
+            let value = realm.eval(source);
+            // a fatal error leaves nothing to pop
+            if !realm.is_poisoned() {
+                realm.pop_it();
+            }
+            value
+        })?
+    }
+
+    /// Call the realm's global function `name`.
+    pub fn call<A, R>(&self, ctx: &mut Context, name: &str, args: A) -> Result<R, Error>
+    where
+        A: PushArgs,
+        R: PeekValue,
+    {
+        self.with(ctx, |realm| {
+            let f: JsFunction = JsObject::global(realm).get(realm, name)?;
+            f.call(realm, args)
+        })?
+    }
+
+    /// The realm's global object.
+    pub fn global(&self, ctx: &mut Context) -> Result<JsObject, Error> {
+        self.with(ctx, JsObject::global)
+    }
+
+    /// Copy the globals `names`, usually host functions, from the context
+    /// into the realm.
+    ///
+    /// Native functions look up globals in the realm calling them, script
+    /// functions in the realm they were defined in.
+    pub fn copy_globals(&self, ctx: &mut Context, names: &[&str]) -> Result<(), Error> {
+        let from = JsObject::global(ctx);
+        let to = self.global(ctx)?;
+        for name in names {
+            let value: JsRef = from.get(ctx, name)?;
+            to.set(ctx, name, &value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolated_globals() {
+        let mut ctx = Context::default();
+        let a = ctx.new_realm();
+        let b = ctx.new_realm();
+        a.eval::<()>(
+            &mut ctx,
+            "var x = 1; Array.prototype.sum = function () { return 1 }",
+        )
+        .unwrap();
+        b.eval::<()>(&mut ctx, "var x = 2").unwrap();
+
+        assert_eq!(a.eval::<u32>(&mut ctx, "x").unwrap(), 1);
+        assert_eq!(b.eval::<u32>(&mut ctx, "x").unwrap(), 2);
+        assert!(b.eval::<bool>(&mut ctx, "[].sum === undefined").unwrap());
+        assert!(ctx.eval::<bool>("typeof x === 'undefined'").unwrap());
+        ctx.pop_it();
+
+        let global = b.global(&mut ctx).unwrap();
+        assert_eq!(global.get::<u32>(&mut ctx, "x").unwrap(), 2);
+        assert!(matches!(a.eval::<()>(&mut ctx, "y"), Err(Error::Js(_))));
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn call_and_copy() {
+        let mut ctx = Context::default();
+        ctx.register_closure("set_x", |ctx, args| {
+            let x: u32 = args.get(ctx, 0).unwrap();
+            JsObject::global(ctx).set(ctx, "x", x).unwrap();
+        });
+
+        let realm = ctx.new_realm();
+        assert!(realm.call::<_, ()>(&mut ctx, "set_x", (1u32,)).is_err());
+        realm.copy_globals(&mut ctx, &["set_x"]).unwrap();
+        realm.call::<_, ()>(&mut ctx, "set_x", (3u32,)).unwrap();
+        realm
+            .eval::<()>(&mut ctx, "function get_x() { return x }")
+            .unwrap();
+        assert_eq!(realm.call::<_, u32>(&mut ctx, "get_x", ()).unwrap(), 3);
+        assert!(ctx.eval::<bool>("typeof x === 'undefined'").unwrap());
+    }
+
+    #[test]
+    fn poisoned_context() {
+        use crate as duktape;
+        use crate::duktape;
+
+        #[duktape]
+        fn boom(ctx: &mut Context) {
+            let msg = std::ffi::CString::new("boom").unwrap();
+            unsafe { duktape_sys::duk_fatal_raw(ctx.as_raw(), msg.as_ptr()) }
+        }
+
+        let mut ctx = Context::default();
+        ctx.register_function("boom", Boom);
+        let realm = ctx.new_realm();
+        realm.copy_globals(&mut ctx, &["boom"]).unwrap();
+        assert!(matches!(
+            realm.eval::<()>(&mut ctx, "boom()"),
+            Err(Error::Fatal(_))
+        ));
+        assert!(matches!(
+            realm.eval::<()>(&mut ctx, "1"),
+            Err(Error::Poisoned)
+        ));
+        assert!(matches!(
+            realm.call::<_, ()>(&mut ctx, "boom", ()),
+            Err(Error::Poisoned)
+        ));
+        assert!(matches!(realm.global(&mut ctx), Err(Error::Poisoned)));
+    }
+}
@@ -0,0 +1,192 @@
+//! Handles keeping JS values alive independently of the value stack.
+
+use std::rc::Rc;
+
+use crate::heap::{HeapLink, HeapState};
+use crate::value::{PeekError, PeekValue, PushValue};
+use crate::Context;
+
+/// An owned reference to a JS value.
+///
+/// The value is stored in the heap stash, so it stays reachable while any
+/// clone of the handle exists and is released when the last one is dropped.
+/// Handles may outlive their [`Context`], but can only be pushed to a
+/// context of the heap they were created in.
+///
+/// ```
+///     use duktape::{Context, JsRef};
+///
+///     let mut ctx = Context::default();
+///     let obj: JsRef = ctx.eval("({answer: 42})").unwrap();
+///     ctx.pop().unwrap();
+///
+///     ctx.push(&obj);
+///     ctx.get_prop(-1, "answer");
+///     assert_eq!(ctx.get_uint(-1), 42);
+/// ```
+#[derive(Clone)]
+pub struct JsRef {
+    slot: Rc<Slot>,
+}
+
+struct Slot {
+    heap: HeapLink,
+    key: u32,
+}
+
+impl JsRef {
+    /// Reference the value at `idx`, leaving the stack as it is.
+    pub fn new(ctx: &mut Context, idx: i32) -> Self {
+        unsafe { duktape_sys::duk_dup(ctx.inner, idx) };
+        Self::from_top(ctx)
+    }
+
+    /// Move the value on top of the stack into a new stash slot.
+    pub(crate) fn from_top(ctx: &mut Context) -> Self {
+        let heap = ctx.heap().link.clone();
+        let key = ctx.heap().new_slot();
+        unsafe {
+            duktape_sys::duk_push_heap_stash(ctx.inner);
+            duktape_sys::duk_swap_top(ctx.inner, -2);
+            duktape_sys::duk_put_prop_index(ctx.inner, -2, key);
+        }
+        ctx.pop_it();
+        JsRef {
+            slot: Rc::new(Slot { heap, key }),
+        }
+    }
+}
+
+impl PushValue for &JsRef {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        assert!(
+            Rc::ptr_eq(&self.slot.heap, &ctx.heap().link),
+            "reference used with a context of another heap"
+        );
+        unsafe {
+            duktape_sys::duk_push_heap_stash(ctx.inner);
+            duktape_sys::duk_get_prop_index(ctx.inner, -1, self.slot.key);
+            duktape_sys::duk_remove(ctx.inner, -2);
+        }
+        ctx.stack_top()
+    }
+}
+
+impl PushValue for JsRef {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        ctx.push(&self)
+    }
+}
+
+impl PeekValue for JsRef {
+    fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
+        Ok(JsRef::new(ctx, idx))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let inner = self.heap.get();
+        // the heap and everything in it is gone already
+        if inner.is_null() {
+            return;
+        }
+        let state = unsafe { HeapState::from_ctx(inner) };
+        // like the heap itself, a poisoned heap's stash is left alone
+        if state.poisoned.get() {
+            return;
+        }
+        unsafe {
+            duktape_sys::duk_push_heap_stash(inner);
+            duktape_sys::duk_del_prop_index(inner, -1, self.key);
+            duktape_sys::duk_pop(inner);
+        }
+        state.release_slot(self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_stack() {
+        let mut ctx = Context::default();
+        let obj: JsRef = ctx.eval("({items: [1, 2, 3]})").unwrap();
+        ctx.pop_it();
+        ctx.eval::<()>("for (var i = 0; i < 1000; i++) { [i] }")
+            .unwrap();
+        ctx.pop_it();
+        unsafe { duktape_sys::duk_gc(ctx.inner, 0) };
+
+        let copy = obj.clone();
+        drop(obj);
+        ctx.push(&copy);
+        ctx.get_prop(-1, "items");
+        assert_eq!(ctx.get_length(-1), 3);
+        ctx.pop_n(2);
+    }
+
+    #[test]
+    fn released_on_drop() {
+        let mut ctx = Context::default();
+        ctx.eval::<()>("var collected = false").unwrap();
+        ctx.pop_it();
+        let obj: JsRef = ctx
+            .eval("var o = {}; Duktape.fin(o, function() { collected = true }); o")
+            .unwrap();
+        ctx.pop_it();
+        ctx.eval::<()>("o = null").unwrap();
+        ctx.pop_it();
+
+        unsafe { duktape_sys::duk_gc(ctx.inner, 0) };
+        assert!(!ctx.eval::<bool>("collected").unwrap());
+        ctx.pop_it();
+
+        drop(obj);
+        unsafe { duktape_sys::duk_gc(ctx.inner, 0) };
+        assert!(ctx.eval::<bool>("collected").unwrap());
+    }
+
+    #[test]
+    fn reuses_released_slots() {
+        let mut ctx = Context::default();
+        let first: JsRef = ctx.eval("'first'").unwrap();
+        let second: JsRef = ctx.eval("'second'").unwrap();
+        ctx.pop_n(2);
+        drop(first);
+        let third: JsRef = ctx.eval("'third'").unwrap();
+        ctx.pop_it();
+        assert_eq!(third.slot.key, 0);
+
+        ctx.push(&second);
+        ctx.push(&third);
+        assert_eq!(ctx.pop_value::<String>().unwrap(), "third");
+        assert_eq!(ctx.pop_value::<String>().unwrap(), "second");
+    }
+
+    #[test]
+    fn dropped_in_poisoned_context() {
+        let mut ctx = Context::default();
+        let value: JsRef = ctx.eval("[]").unwrap();
+        ctx.pop_it();
+        let key = value.slot.key;
+
+        ctx.push_string("not a number");
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ctx.get_uint(-1)));
+        assert!(res.is_err());
+        assert!(ctx.is_poisoned());
+
+        // the slot isn't touched, nor handed out again
+        drop(value);
+        assert_ne!(ctx.heap().new_slot(), key);
+    }
+
+    #[test]
+    fn outlives_context() {
+        let mut ctx = Context::default();
+        let value: JsRef = ctx.eval("[]").unwrap();
+        drop(ctx);
+        drop(value);
+    }
+}
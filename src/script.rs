@@ -0,0 +1,201 @@
+//! Compiling scripts once and running them many times.
+
+use crate::value::{PeekValue, PushValue};
+use crate::{Context, Error, JsError, JsRef};
+
+/// How [`Context::compile`] treats its source.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Name shown in stack traces and error locations.
+    pub filename: Option<String>,
+    /// Compile as strict mode code.
+    pub strict: bool,
+    /// The source is a single function expression like `function (a, b) { ... }`
+    /// rather than a program.
+    pub function_mode: bool,
+}
+
+impl CompileOptions {
+    fn flags(&self) -> u32 {
+        use duktape_sys::{
+            DUK_COMPILE_FUNCTION, DUK_COMPILE_NOFILENAME, DUK_COMPILE_NOSOURCE, DUK_COMPILE_SAFE,
+            DUK_COMPILE_STRICT,
+        };
+
+        let mut flags = DUK_COMPILE_SAFE | DUK_COMPILE_NOSOURCE;
+        match self.filename {
+            // the filename is the single argument of the protected call
+            Some(_) => flags |= 1,
+            None => flags |= DUK_COMPILE_NOFILENAME,
+        }
+        if self.strict {
+            flags |= DUK_COMPILE_STRICT;
+        }
+        if self.function_mode {
+            flags |= DUK_COMPILE_FUNCTION;
+        }
+        flags
+    }
+}
+
+/// A compiled script, kept alive until dropped.
+///
+/// Programs run with [`Script::run`], functions compiled with
+/// [`CompileOptions::function_mode`] are pushed with `ctx.push(&script)`
+/// and called like any other function.
+pub struct Script {
+    function: JsRef,
+}
+
+impl Context {
+    /// Compile `source` without running it.
+    ///
+    /// Syntax errors are returned as [`Error::Js`] with `line_number` set to
+    /// the line the compiler gave up on. Duktape doesn't track columns.
+    /// Like with [`Context::eval`] the error is left on top of the stack.
+    pub fn compile(&mut self, source: &str, options: CompileOptions) -> Result<Script, Error> {
+        self.protect(|ctx| {
+            if let Some(filename) = &options.filename {
+                ctx.push_string(filename);
+            }
+            let rv = unsafe {
+                duktape_sys::duk_compile_raw(
+                    ctx.inner,
+                    source.as_ptr() as *const i8,
+                    source.len() as u64,
+                    options.flags(),
+                )
+            };
+            if rv != 0 {
+                let mut error = ctx.take_error();
+                if let Error::Js(error) = &mut error {
+                    syntax_error_location(error, &options);
+                }
+                return Err(error);
+            }
+            Ok(Script::from_top(ctx))
+        })
+    }
+}
+
+// Compile errors carry the location as part of the message only.
+fn syntax_error_location(error: &mut JsError, options: &CompileOptions) {
+    if error.code != duktape_sys::DUK_ERR_SYNTAX_ERROR as i32 {
+        return;
+    }
+    let line = error
+        .message
+        .rfind("(line ")
+        .map(|at| &error.message[at + "(line ".len()..])
+        .and_then(|rest| {
+            let end = rest.find(|c: char| !c.is_ascii_digit())?;
+            rest[..end].parse().ok()
+        });
+    if line.is_some() {
+        error.line_number = line;
+        error.file_name = options.filename.clone();
+    }
+}
+
+impl Script {
+    pub(crate) fn from_top(ctx: &mut Context) -> Self {
+        Script {
+            function: JsRef::from_top(ctx),
+        }
+    }
+
+    /// Run a compiled program, leaving its result on the stack like
+    /// [`Context::eval`].
+    pub fn run<T: PeekValue>(&self, ctx: &mut Context) -> Result<T, Error> {
+        ctx.push(self);
+        ctx.call(0)?;
+        ctx.peek(-1).map_err(Error::Peek)
+    }
+}
+
+impl PushValue for &Script {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        ctx.push(&self.function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_many_times() {
+        let mut ctx = Context::default();
+        let script = ctx
+            .compile("var n = (this.n || 0) + 1; n", CompileOptions::default())
+            .unwrap();
+        for i in 1..=3 {
+            let n: u32 = script.run(&mut ctx).unwrap();
+            assert_eq!(n, i);
+            ctx.pop_it();
+        }
+        drop(script);
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn function_mode() {
+        let mut ctx = Context::default();
+        let add = ctx
+            .compile(
+                "function (a, b) { return a + b }",
+                CompileOptions {
+                    function_mode: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        ctx.push(&add);
+        ctx.push_int(2);
+        ctx.push_int(3);
+        ctx.call(2).unwrap();
+        assert_eq!(ctx.get_int(-1), 5);
+    }
+
+    #[test]
+    fn errors_have_filename() {
+        let mut ctx = Context::default();
+        let options = CompileOptions {
+            filename: Some("rules.js".to_string()),
+            strict: true,
+            ..Default::default()
+        };
+        let script = ctx.compile("\n\nundeclared = 1", options.clone()).unwrap();
+        match script.run::<()>(&mut ctx) {
+            Err(Error::Js(e)) => {
+                assert_eq!(e.name.as_deref(), Some("ReferenceError"));
+                assert_eq!(e.file_name.as_deref(), Some("rules.js"));
+                assert_eq!(e.line_number, Some(3));
+                assert!(e.stack.unwrap().contains("rules.js:3"));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        ctx.pop_it();
+
+        match ctx.compile("var a = 1;\nvar = 2;", options) {
+            Err(Error::Js(e)) => {
+                assert_eq!(e.name.as_deref(), Some("SyntaxError"));
+                assert_eq!(e.file_name.as_deref(), Some("rules.js"));
+                assert_eq!(e.line_number, Some(2));
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("compiled invalid script"),
+        }
+        assert_eq!(ctx.stack_len(), 1);
+        ctx.pop_it();
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn outlives_context() {
+        let mut ctx = Context::default();
+        let script = ctx.compile("1", CompileOptions::default()).unwrap();
+        drop(ctx);
+        drop(script);
+    }
+}
@@ -0,0 +1,233 @@
+//! Moving a heap between OS threads.
+//!
+//! A heap may be used from any thread as long as it's used by one thread
+//! at a time, but values it owns may not: closures and `Rc`s pushed by
+//! the host, and handles like [`JsRef`](crate::JsRef) sharing an `Rc`
+//! with the context.
+//!
+//! While not in use, the heap is suspended with `duk_suspend`, so no
+//! engine state refers to the thread which used it last.
+
+use crate::closure::Args;
+use crate::value::PushValue;
+use crate::Context;
+
+/// A [`Context`] which can be sent to other threads.
+///
+/// Handles into the heap can't be returned from [`SendContext::with`]
+/// since they aren't `Send`, and closures registered through
+/// [`SendContext::register_closure`] must be `Send` themselves. Pushing
+/// values which aren't `Send` through the `Context` API inside `with`
+/// is caught when it returns, the heap is then destroyed on the calling
+/// thread together with those values and the `SendContext` can't be used
+/// anymore.
+///
+/// ```
+///     use duktape::{Context, SendContext};
+///
+///     let mut ctx = SendContext::new(Context::default()).ok().unwrap();
+///     ctx.register_closure("double", |ctx, args| 2 * args.get::<u32>(ctx, 0).unwrap());
+///     ctx.with(|ctx| ctx.eval::<()>("var n = double(2)").unwrap());
+///
+///     let n = std::thread::spawn(move || ctx.with(|ctx| ctx.eval::<u32>("double(n)").unwrap()))
+///         .join()
+///         .unwrap();
+///     assert_eq!(n, 8);
+/// ```
+pub struct SendContext {
+    /// `None` once discarded for leaving values tied to a thread.
+    ctx: Option<Context>,
+    state: Option<Box<duktape_sys::duk_thread_state>>,
+}
+
+// Safety: the context can only be used through `with`, which requires
+// exclusive access and destroys the heap unless nothing owned by it is
+// tied to the calling thread when it returns or unwinds.
+unsafe impl Send for SendContext {}
+
+impl SendContext {
+    /// Wrap `ctx`, or give it back if handles into its heap are alive or
+    /// it owns values which aren't `Send`.
+    pub fn new(ctx: Context) -> Result<Self, Context> {
+        if !is_sendable(&ctx) {
+            return Err(ctx);
+        }
+        let mut ctx = SendContext {
+            ctx: Some(ctx),
+            state: None,
+        };
+        ctx.suspend();
+        Ok(ctx)
+    }
+
+    /// Use the context on the current thread.
+    ///
+    /// Values left on the value stack are kept for the next call.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` left the heap owning values which aren't `Send`, or
+    /// stored handles into the heap somewhere else. The heap is destroyed
+    /// in that case, as it is when `f` panics and leaves such values, and
+    /// every later call panics too.
+    pub fn with<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Context) -> R,
+        R: Send,
+    {
+        assert!(self.ctx.is_some(), "{}", DISCARDED);
+        self.resume();
+        let guard = Suspend(self);
+        let value = f(guard.0.ctx.as_mut().unwrap());
+        assert!(
+            is_sendable(guard.0.ctx.as_ref().unwrap()),
+            "values tied to a thread left in a SendContext"
+        );
+        value
+    }
+
+    /// Make `f` available to scripts as the global function `name`.
+    pub fn register_closure<F, R>(&mut self, name: &str, f: F)
+    where
+        F: FnMut(&mut Context, Args) -> R + Send + 'static,
+        R: PushValue,
+    {
+        self.with(|ctx| {
+            ctx.push_send_closure(f);
+            ctx.put_global_string(name);
+        })
+    }
+
+    /// Take the context back, tying it to the current thread again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap was destroyed by [`SendContext::with`].
+    pub fn into_inner(mut self) -> Context {
+        self.resume();
+        self.ctx.take().expect(DISCARDED)
+    }
+
+    fn suspend(&mut self) {
+        let Some(ctx) = &self.ctx else {
+            return;
+        };
+        if ctx.is_poisoned() {
+            return;
+        }
+        let mut state = Box::new(duktape_sys::duk_thread_state { data: [0; 128] });
+        unsafe { duktape_sys::duk_suspend(ctx.inner, &mut *state) };
+        self.state = Some(state);
+    }
+
+    fn resume(&mut self) {
+        if let (Some(ctx), Some(state)) = (&self.ctx, self.state.take()) {
+            unsafe { duktape_sys::duk_resume(ctx.inner, &*state) };
+        }
+    }
+}
+
+const DISCARDED: &str = "SendContext used after values tied to a thread were left in it";
+
+impl Drop for SendContext {
+    fn drop(&mut self) {
+        self.resume();
+    }
+}
+
+// Suspends the heap again when `with` returns or unwinds, or destroys it
+// right here if it can't leave the thread anymore.
+struct Suspend<'a>(&'a mut SendContext);
+
+impl Drop for Suspend<'_> {
+    fn drop(&mut self) {
+        if self.0.ctx.as_ref().is_some_and(is_sendable) {
+            self.0.suspend();
+        } else {
+            self.0.ctx = None;
+        }
+    }
+}
+
+fn is_sendable(ctx: &Context) -> bool {
+    let heap = ctx.heap();
+    heap.local_values.get() == 0 && std::rc::Rc::strong_count(&heap.link) == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ContextBuilder, JsObject};
+    use std::rc::Rc;
+
+    #[test]
+    fn move_between_threads() {
+        let mut ctx = SendContext::new(Context::default()).ok().unwrap();
+        ctx.with(|ctx| {
+            ctx.eval::<()>("var calls = 0").unwrap();
+            ctx.pop_it();
+        });
+        for _ in 0..3 {
+            ctx = std::thread::spawn(move || {
+                ctx.with(|ctx| {
+                    ctx.eval::<()>("calls++").unwrap();
+                    ctx.pop_it();
+                });
+                ctx
+            })
+            .join()
+            .unwrap();
+        }
+        let mut ctx = ctx.into_inner();
+        assert_eq!(ctx.eval::<u32>("calls").unwrap(), 3);
+    }
+
+    #[test]
+    fn rejects_local_values() {
+        let mut ctx = Context::default();
+        let global = JsObject::global(&mut ctx);
+        let mut ctx = SendContext::new(ctx).err().unwrap();
+        drop(global);
+
+        ctx.register_closure("f", |_ctx, _args| ());
+        let mut ctx = SendContext::new(ctx).err().unwrap();
+        ctx.eval::<()>("f = undefined").unwrap();
+        ctx.pop_it();
+        let mut ctx = SendContext::new(ctx).ok().unwrap();
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            ctx.with(|ctx| ctx.push(Rc::new(1u32)));
+        }));
+        assert!(res.is_err());
+
+        let ctx = ContextBuilder::new().fatal_handler(|_| ()).build().unwrap();
+        assert!(SendContext::new(ctx).is_err());
+    }
+
+    #[test]
+    fn discarded_after_leaking_local_values() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let value = Rc::new(1u32);
+        let mut ctx = SendContext::new(Context::default()).ok().unwrap();
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            ctx.with(|ctx| ctx.push(value.clone()));
+        }));
+        assert!(res.is_err());
+        // the heap and the value it owned are gone already
+        assert_eq!(Rc::strong_count(&value), 1);
+        assert!(catch_unwind(AssertUnwindSafe(|| ctx.with(|_ctx| ()))).is_err());
+
+        // also when the closure panics itself
+        let mut ctx = SendContext::new(Context::default()).ok().unwrap();
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            ctx.with(|ctx| {
+                ctx.push(value.clone());
+                panic!("oops");
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(Rc::strong_count(&value), 1);
+        assert!(catch_unwind(AssertUnwindSafe(|| ctx.into_inner())).is_err());
+    }
+}
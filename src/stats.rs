@@ -0,0 +1,315 @@
+//! Memory statistics of a heap and of single values.
+
+use crate::Context;
+
+/// Memory use of a heap, see [`Context::heap_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes currently allocated, as seen by the allocator.
+    pub allocated: usize,
+    /// Most bytes allocated at once since the heap was created.
+    pub peak: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Objects, including functions, threads and the built-ins.
+    pub objects: usize,
+    pub strings: usize,
+    /// Plain buffers, buffer objects like `Uint8Array` count as objects.
+    pub buffers: usize,
+}
+
+/// Type of a value as seen by the engine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueType {
+    /// No value, the index was outside the stack.
+    #[default]
+    None,
+    Undefined,
+    Null,
+    Boolean,
+    Number,
+    String,
+    Object,
+    Buffer,
+    Pointer,
+    LightFunc,
+}
+
+impl ValueType {
+    /// The type for one of the `DUK_TYPE_*` numbers.
+    pub fn from_raw(value_type: u32) -> Option<Self> {
+        use duktape_sys::*;
+
+        Some(match value_type {
+            DUK_TYPE_NONE => ValueType::None,
+            DUK_TYPE_UNDEFINED => ValueType::Undefined,
+            DUK_TYPE_NULL => ValueType::Null,
+            DUK_TYPE_BOOLEAN => ValueType::Boolean,
+            DUK_TYPE_NUMBER => ValueType::Number,
+            DUK_TYPE_STRING => ValueType::String,
+            DUK_TYPE_OBJECT => ValueType::Object,
+            DUK_TYPE_BUFFER => ValueType::Buffer,
+            DUK_TYPE_POINTER => ValueType::Pointer,
+            DUK_TYPE_LIGHTFUNC => ValueType::LightFunc,
+            _ => return None,
+        })
+    }
+}
+
+macro_rules! object_classes {
+    ($($variant: ident = $name: literal,)*) => {
+        /// Internal class of an object, in the order of the engine's
+        /// `DUK_HOBJECT_CLASS_*` numbers.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum ObjectClass {
+            $($variant,)*
+        }
+
+        impl ObjectClass {
+            const ALL: &'static [ObjectClass] = &[$(ObjectClass::$variant,)*];
+
+            /// Name of the class as shown to users, like `Uint8Array`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(ObjectClass::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+object_classes! {
+    // objects without a class, like bare objects
+    Unspecified = "Object",
+    Object = "Object",
+    Array = "Array",
+    Function = "Function",
+    Arguments = "Arguments",
+    Boolean = "Boolean",
+    Date = "Date",
+    Error = "Error",
+    Json = "JSON",
+    Math = "Math",
+    Number = "Number",
+    RegExp = "RegExp",
+    String = "String",
+    Global = "global",
+    Symbol = "Symbol",
+    ObjEnv = "ObjEnv",
+    DecEnv = "DecEnv",
+    Pointer = "Pointer",
+    Thread = "Thread",
+    ArrayBuffer = "ArrayBuffer",
+    DataView = "DataView",
+    Int8Array = "Int8Array",
+    Uint8Array = "Uint8Array",
+    Uint8ClampedArray = "Uint8ClampedArray",
+    Int16Array = "Int16Array",
+    Uint16Array = "Uint16Array",
+    Int32Array = "Int32Array",
+    Uint32Array = "Uint32Array",
+    Float32Array = "Float32Array",
+    Float64Array = "Float64Array",
+}
+
+impl ObjectClass {
+    /// The class for one of the `DUK_HOBJECT_CLASS_*` numbers.
+    pub fn from_raw(class: u32) -> Option<Self> {
+        Self::ALL.get(class as usize).copied()
+    }
+}
+
+/// What the engine knows about a value, see [`Context::inspect`].
+///
+/// Fields which don't apply to the value are `None`, primitive values like
+/// numbers only have a type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValueInfo {
+    pub value_type: ValueType,
+    /// Address of the heap allocated value.
+    pub pointer: Option<usize>,
+    pub refcount: Option<usize>,
+    /// Bytes of the value's own allocation, including the characters of a
+    /// string or the data of a fixed buffer.
+    pub heap_size: Option<usize>,
+    pub class: Option<ObjectClass>,
+    /// Bytes allocated for the property table of an object.
+    pub property_size: Option<usize>,
+    /// Slots of the property table for named properties.
+    pub entry_size: Option<usize>,
+    /// Named property slots in use, deleted properties included.
+    pub entry_used: Option<usize>,
+    /// Slots for array items stored without keys.
+    pub array_size: Option<usize>,
+    /// Slots of the hash index over the named properties.
+    pub hash_size: Option<usize>,
+    /// Bytes of bytecode, constants and inner functions of a script function.
+    pub bytecode_size: Option<usize>,
+    /// Bytes of the separately allocated data of a dynamic buffer.
+    pub data_size: Option<usize>,
+}
+
+impl Context {
+    /// Memory use of the heap.
+    ///
+    /// Byte and allocation counts are kept by the allocator, value counts
+    /// come from walking the engine's lists of live values, so they include
+    /// garbage which wasn't collected yet.
+    ///
+    /// ```
+    ///     use duktape::Context;
+    ///
+    ///     let mut ctx = Context::default();
+    ///     let before = ctx.heap_stats();
+    ///     ctx.eval::<()>("var objects = []; for (var i = 0; i < 100; i++) objects.push({})").unwrap();
+    ///     let after = ctx.heap_stats();
+    ///     assert!(after.objects >= before.objects + 100);
+    ///     assert!(after.allocated > before.allocated);
+    /// ```
+    pub fn heap_stats(&self) -> HeapStats {
+        let memory = &self.heap().memory;
+        let (mut objects, mut strings, mut buffers) = (0, 0, 0);
+        unsafe {
+            duktape_sys::duk_rs_get_heap_counts(
+                self.inner,
+                &mut objects,
+                &mut strings,
+                &mut buffers,
+            )
+        };
+        HeapStats {
+            allocated: memory.allocated(),
+            peak: memory.peak(),
+            allocations: memory.blocks(),
+            objects: objects as usize,
+            strings: strings as usize,
+            buffers: buffers as usize,
+        }
+    }
+
+    /// Sizes and reference count of the value at `idx`.
+    ///
+    /// ```
+    ///     use duktape::Context;
+    ///
+    ///     let mut ctx = Context::default();
+    ///     ctx.eval::<()>("var big = []; for (var i = 0; i < 1000; i++) big.push(i)").unwrap();
+    ///     ctx.get_global_str("big");
+    ///     let info = ctx.inspect(-1);
+    ///     assert!(info.array_size.unwrap() >= 1000);
+    /// ```
+    pub fn inspect(&mut self, idx: i32) -> ValueInfo {
+        let raw = self.inner;
+        // reads a property of the info object, missing ones don't apply
+        let field = |name: &str| unsafe {
+            duktape_sys::duk_get_prop_lstring(raw, -1, name.as_ptr() as *const _, name.len() as _);
+            let value = match duktape_sys::duk_is_number(raw, -1) {
+                0 => None,
+                _ => Some(duktape_sys::duk_get_uint(raw, -1) as usize),
+            };
+            duktape_sys::duk_pop(raw);
+            value
+        };
+        unsafe { duktape_sys::duk_inspect_value(raw, idx) };
+        let pointer = unsafe {
+            duktape_sys::duk_get_prop_lstring(raw, -1, "hptr".as_ptr() as *const _, 4);
+            let pointer = duktape_sys::duk_get_pointer(raw, -1);
+            duktape_sys::duk_pop(raw);
+            (!pointer.is_null()).then_some(pointer as usize)
+        };
+        let info = ValueInfo {
+            value_type: field("type")
+                .and_then(|value_type| ValueType::from_raw(value_type as u32))
+                .unwrap_or_default(),
+            pointer,
+            refcount: field("refc"),
+            heap_size: field("hbytes"),
+            class: field("class").and_then(|class| ObjectClass::from_raw(class as u32)),
+            property_size: field("pbytes"),
+            entry_size: field("esize"),
+            entry_used: field("enext"),
+            array_size: field("asize"),
+            hash_size: field("hsize"),
+            bytecode_size: field("bcbytes"),
+            data_size: field("dbytes"),
+        };
+        self.pop_it();
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heap_counts() {
+        let mut ctx = Context::default();
+        let before = ctx.heap_stats();
+        assert!(before.objects > 0 && before.strings > 0);
+        assert!(before.peak >= before.allocated);
+
+        ctx.eval::<()>(
+            "var keep = []; for (var i = 0; i < 50; i++) keep.push({}, 'str' + i, Uint8Array.allocPlain(8))",
+        )
+        .unwrap();
+        let after = ctx.heap_stats();
+        assert!(after.objects >= before.objects + 50);
+        assert!(after.strings >= before.strings + 50);
+        assert!(after.buffers >= before.buffers + 50);
+        assert!(after.allocations > before.allocations);
+
+        ctx.eval::<()>("keep = null").unwrap();
+        unsafe { duktape_sys::duk_gc(ctx.as_raw(), 0) };
+        let collected = ctx.heap_stats();
+        assert!(collected.objects < after.objects);
+        assert!(collected.buffers < after.buffers);
+        assert!(collected.allocated < after.allocated);
+        assert_eq!(collected.peak, after.peak.max(collected.peak));
+    }
+
+    #[test]
+    fn inspect_values() {
+        let mut ctx = Context::default();
+        ctx.push_int(1);
+        let info = ctx.inspect(-1);
+        assert_eq!(info.value_type, ValueType::Number);
+        assert_eq!(
+            info,
+            ValueInfo {
+                value_type: info.value_type,
+                ..Default::default()
+            }
+        );
+
+        ctx.push_string("hello");
+        let info = ctx.inspect(-1);
+        assert_eq!(info.value_type, ValueType::String);
+        assert!(info.heap_size.unwrap() > 5);
+        assert!(info.pointer.is_some() && info.class.is_none());
+
+        ctx.eval::<()>("var obj = {a: 1, b: 2, c: 3}; var alias = obj")
+            .unwrap();
+        ctx.get_global_str("obj");
+        let len = ctx.stack_len();
+        let info = ctx.inspect(-1);
+        assert_eq!(info.class, Some(ObjectClass::Object));
+        assert_eq!(info.entry_used, Some(3));
+        assert!(info.entry_size.unwrap() >= 3);
+        // the two globals and the stack
+        assert!(info.refcount.unwrap() >= 3);
+        assert_eq!(ctx.stack_len(), len);
+
+        ctx.eval::<()>("function f(a) { return a + 1 }").unwrap();
+        ctx.get_global_str("f");
+        let info = ctx.inspect(-1);
+        assert_eq!(info.class, Some(ObjectClass::Function));
+        assert!(info.bytecode_size.unwrap() > 0);
+
+        ctx.eval::<()>("var bytes = new Uint8Array(4)").unwrap();
+        ctx.get_global_str("bytes");
+        let info = ctx.inspect(-1);
+        assert_eq!(info.value_type, ValueType::Object);
+        assert_eq!(info.class.map(ObjectClass::name), Some("Uint8Array"));
+    }
+}
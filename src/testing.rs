@@ -0,0 +1,10 @@
+//! Helpers shared by the unit tests.
+
+use crate::{Context, PeekValue};
+
+/// Evaluate `source`, popping the result off the stack.
+pub(crate) fn eval<T: PeekValue>(ctx: &mut Context, source: &str) -> T {
+    let value = ctx.eval(source).unwrap();
+    ctx.pop_it();
+    value
+}
@@ -0,0 +1,238 @@
+//! Coroutines built on Duktape threads.
+//!
+//! `Duktape.Thread.resume` and `Duktape.Thread.yield` may only be called
+//! from ECMAScript functions, so resuming goes through a small script
+//! helper, which also records when the coroutine has finished.
+
+use crate::value::{PeekValue, PushValue};
+use crate::{CompileOptions, Context, Error, JsFunction, JsObject, JsRef};
+
+const HELPER_PROP: &[u8] = b"\xffcoroutine";
+
+const HELPER: &str = "({
+    spawn: function (fn) {
+        var state = { done: false };
+        state.thread = new Duktape.Thread(function (value) {
+            try {
+                return fn(value);
+            } finally {
+                state.done = true;
+            }
+        });
+        return state;
+    },
+    resume: function (state, value) {
+        return Duktape.Thread.resume(state.thread, value);
+    }
+})";
+
+/// Value a coroutine handed back to [`JsThread::resume`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resumed<T> {
+    /// Passed to `Duktape.Thread.yield`, the coroutine may be resumed again.
+    Yield(T),
+    /// Returned by the coroutine, which has finished.
+    Return(T),
+}
+
+/// A JS function running as a coroutine.
+///
+/// The function must be an ECMAScript function, which pauses itself by
+/// calling `Duktape.Thread.yield(value)`.
+///
+/// ```
+///     use duktape::{Context, JsFunction, JsThread};
+///
+///     let mut ctx = Context::default();
+///     let steps: JsFunction = ctx
+///         .eval("(function (n) { while (n > 0) { Duktape.Thread.yield(n--) } })")
+///         .unwrap();
+///     ctx.pop().unwrap();
+///
+///     let mut thread = JsThread::new(&mut ctx, &steps, 3u32).unwrap();
+///     let yielded: Vec<u32> = thread.yields(&mut ctx).map(Result::unwrap).collect();
+///     assert_eq!(yielded, [3, 2, 1]);
+/// ```
+pub struct JsThread {
+    state: JsObject,
+    // passed to the first resume
+    argument: Option<JsRef>,
+}
+
+impl JsThread {
+    /// Prepare `f` to be called with `argument` by the first resume.
+    pub fn new<T: PushValue>(
+        ctx: &mut Context,
+        f: &JsFunction,
+        argument: T,
+    ) -> Result<Self, Error> {
+        let helper = helper(ctx)?;
+        let spawn: JsFunction = helper.get(ctx, "spawn")?;
+        let state = spawn.call(ctx, (f,))?;
+        ctx.push(argument);
+        let argument = JsRef::from_top(ctx);
+        Ok(JsThread {
+            state,
+            argument: Some(argument),
+        })
+    }
+
+    /// Whether the coroutine has returned or thrown.
+    pub fn is_done(&self, ctx: &mut Context) -> Result<bool, Error> {
+        self.state.get(ctx, "done")
+    }
+
+    /// Continue the coroutine until it yields or returns, `value` is
+    /// returned by the `Duktape.Thread.yield` call it's paused in.
+    ///
+    /// The value passed to the first resume is ignored, the coroutine
+    /// starts with the argument given to [`JsThread::new`].
+    pub fn resume<T, R>(&mut self, ctx: &mut Context, value: T) -> Result<Resumed<R>, Error>
+    where
+        T: PushValue,
+        R: PeekValue,
+    {
+        let (done, value) = self.step(ctx, value)?;
+        ctx.push(&value);
+        let value = ctx.peek_protected()?.map_err(Error::Peek)?;
+        Ok(if done {
+            Resumed::Return(value)
+        } else {
+            Resumed::Yield(value)
+        })
+    }
+
+    /// Iterate over yielded values, resuming with `undefined` each time.
+    ///
+    /// Iteration stops when the coroutine returns, its return value is
+    /// skipped, or after the first error.
+    pub fn yields<'a, R: PeekValue>(&'a mut self, ctx: &'a mut Context) -> Yields<'a, R> {
+        Yields {
+            thread: self,
+            ctx,
+            finished: false,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    fn step<T: PushValue>(&mut self, ctx: &mut Context, value: T) -> Result<(bool, JsRef), Error> {
+        if self.is_done(ctx)? {
+            return Err(Error::Message("coroutine has finished".to_string()));
+        }
+        let helper = helper(ctx)?;
+        let resume: JsFunction = helper.get(ctx, "resume")?;
+        let value = match self.argument.take() {
+            Some(argument) => resume.call(ctx, (&self.state, &argument))?,
+            None => resume.call(ctx, (&self.state, value))?,
+        };
+        Ok((self.is_done(ctx)?, value))
+    }
+}
+
+/// Iterator returned by [`JsThread::yields`].
+pub struct Yields<'a, R> {
+    thread: &'a mut JsThread,
+    ctx: &'a mut Context,
+    finished: bool,
+    _marker: std::marker::PhantomData<R>,
+}
+
+impl<'a, R: PeekValue> Iterator for Yields<'a, R> {
+    type Item = Result<R, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let value = match self.thread.step(self.ctx, ()) {
+            Ok((false, value)) => value,
+            Ok((true, _)) => {
+                self.finished = true;
+                return None;
+            }
+            Err(e) => {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        };
+        self.ctx.push(&value);
+        let value = self.ctx.peek_protected();
+        Some(value.and_then(|value| value.map_err(Error::Peek)))
+    }
+}
+
+// The helper object is compiled once per heap and kept in the stash.
+fn helper(ctx: &mut Context) -> Result<JsObject, Error> {
+    if let Some(helper) = ctx.get_stashed(HELPER_PROP)? {
+        return Ok(helper);
+    }
+    let script = ctx.compile(HELPER, CompileOptions::default())?;
+    let helper: JsObject = script.run(ctx)?;
+    ctx.put_stashed(HELPER_PROP);
+    Ok(helper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::eval;
+
+    #[test]
+    fn resume_with_values() {
+        let mut ctx = Context::default();
+        let sum = eval::<JsFunction>(
+            &mut ctx,
+            "(function (total) {
+                var n;
+                while ((n = Duktape.Thread.yield(total)) !== undefined) {
+                    total += n;
+                }
+                return 'total: ' + total;
+            })",
+        );
+        let mut thread = JsThread::new(&mut ctx, &sum, 1u32).unwrap();
+        assert_eq!(thread.resume(&mut ctx, ()).unwrap(), Resumed::Yield(1u32));
+        assert_eq!(thread.resume(&mut ctx, 2u32).unwrap(), Resumed::Yield(3u32));
+        assert_eq!(thread.resume(&mut ctx, 4u32).unwrap(), Resumed::Yield(7u32));
+        assert_eq!(
+            thread.resume(&mut ctx, None::<u32>).unwrap(),
+            Resumed::Return("total: 7".to_string())
+        );
+        assert!(thread.is_done(&mut ctx).unwrap());
+        assert!(thread.resume::<_, ()>(&mut ctx, ()).is_err());
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn errors_finish_thread() {
+        let mut ctx = Context::default();
+        let fail = eval::<JsFunction>(
+            &mut ctx,
+            "(function () { Duktape.Thread.yield(1); throw new Error('boom') })",
+        );
+        let mut thread = JsThread::new(&mut ctx, &fail, ()).unwrap();
+        let results: Vec<Result<u32, Error>> = thread.yields(&mut ctx).collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap(), &1);
+        match &results[1] {
+            Err(Error::Js(e)) => assert_eq!(e.message, "boom"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(thread.is_done(&mut ctx).unwrap());
+    }
+
+    #[test]
+    fn interleaved() {
+        let mut ctx = Context::default();
+        let count = eval::<JsFunction>(
+            &mut ctx,
+            "(function (from) { for (;;) { Duktape.Thread.yield(from++) } })",
+        );
+        let mut a = JsThread::new(&mut ctx, &count, 0u32).unwrap();
+        let mut b = JsThread::new(&mut ctx, &count, 100u32).unwrap();
+        for i in 0..3 {
+            assert_eq!(a.resume(&mut ctx, ()).unwrap(), Resumed::Yield(i));
+            assert_eq!(b.resume(&mut ctx, ()).unwrap(), Resumed::Yield(100 + i));
+        }
+    }
+}
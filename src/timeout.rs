@@ -0,0 +1,109 @@
+//! Execution deadlines, available with the `exec-timeout` feature.
+//!
+//! The engine is built with an interrupt counter which periodically asks
+//! [`duk_rs_exec_timeout_check`] whether the running script should be
+//! aborted. Once the deadline has passed duktape keeps throwing a
+//! `RangeError` until the script has been unwound completely, so scripts
+//! cannot swallow the timeout with `try/catch`.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::heap::HeapState;
+use crate::{Context, Error};
+
+#[derive(Default)]
+pub(crate) struct Deadline {
+    at: Cell<Option<Instant>>,
+    expired: Cell<bool>,
+}
+
+impl Deadline {
+    /// Returns whether a script was aborted since the last call.
+    pub(crate) fn take_expired(&self) -> bool {
+        self.expired.replace(false)
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn duk_rs_exec_timeout_check(
+    udata: *mut std::ffi::c_void,
+) -> duktape_sys::duk_bool_t {
+    // heaps created directly through duktape_sys have no state attached
+    if udata.is_null() {
+        return 0;
+    }
+    let state = unsafe { &*(udata as *const HeapState) };
+    match state.deadline.at.get() {
+        Some(at) if Instant::now() >= at => {
+            state.deadline.expired.set(true);
+            1
+        }
+        _ => 0,
+    }
+}
+
+impl Context {
+    /// Abort any script still running at `deadline` with [`Error::Timeout`].
+    ///
+    /// The deadline stays in effect for all following calls until it is
+    /// replaced or removed with [`Context::clear_deadline`].
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.heap().deadline.at.set(Some(deadline));
+    }
+
+    pub fn clear_deadline(&mut self) {
+        self.heap().deadline.at.set(None);
+    }
+
+    /// Like [`Context::call`], but gives up after `timeout`.
+    pub fn call_with_timeout(
+        &mut self,
+        n_args: duktape_sys::duk_idx_t,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let previous = self.heap().deadline.at.replace(Some(deadline));
+        let res = self.call(n_args);
+        self.heap().deadline.at.set(previous);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_with_timeout() {
+        let mut ctx = Context::default();
+        ctx.eval::<()>("function spin() { while (true) { try { for (;;) {} } catch (e) {} } }")
+            .unwrap();
+        ctx.pop().unwrap();
+
+        ctx.get_global_str("spin");
+        let res = ctx.call_with_timeout(0, Duration::from_millis(50));
+        assert!(matches!(res, Err(Error::Timeout)));
+        ctx.pop().unwrap();
+
+        // the heap is still usable and the deadline is gone
+        let x: u32 = ctx
+            .eval("var n = 0; for (var i = 0; i < 1000; i++) { n++ }; n")
+            .unwrap();
+        assert_eq!(x, 1000);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut ctx = Context::default();
+        ctx.set_deadline(Instant::now() + Duration::from_millis(20));
+        let res = ctx.eval::<()>("while (true) {}");
+        assert!(matches!(res, Err(Error::Timeout)));
+        ctx.pop().unwrap();
+
+        ctx.clear_deadline();
+        let x: u32 = ctx.eval("1 + 2").unwrap();
+        assert_eq!(x, 3);
+    }
+}
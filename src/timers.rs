@@ -0,0 +1,461 @@
+//! `setTimeout` and `setInterval` driven by the host.
+//!
+//! Timers are kept in a priority queue in the heap state, their callbacks
+//! and arguments in the heap stash. Nothing runs until the host calls
+//! [`Context::run_until_idle`] or [`Context::run_event_loop`], which also
+//! run queued promise jobs after every callback.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::closure::Args;
+use crate::native::Throw;
+use crate::{Context, Error, JsFunction, JsObject, JsRef};
+
+const TABLE_PROP: &[u8] = b"\xfftimers";
+
+// an interval of 0 would keep `run_until_idle` busy forever
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Source of time for timers.
+pub trait Clock: Send {
+    /// Time elapsed since some fixed point.
+    fn now(&self) -> Duration;
+
+    /// Block until [`Clock::now`] reaches `deadline`.
+    fn sleep_until(&self, deadline: Duration);
+}
+
+/// The monotonic system clock.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        std::thread::sleep(deadline.saturating_sub(self.now()));
+    }
+}
+
+/// A clock which only moves when told to, sleeping jumps ahead instantly.
+///
+/// Clones share the same time, keep one to advance the clock installed
+/// into a context.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(deadline);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Timer {
+    at: Duration,
+    // keeps timers due at the same time in the order they were added
+    seq: u64,
+    id: u32,
+    interval: Option<Duration>,
+}
+
+pub(crate) struct Timers {
+    clock: Box<dyn Clock>,
+    queue: BinaryHeap<Reverse<Timer>>,
+    next_id: u32,
+    // once ids wrapped around, those of pending timers are skipped
+    ids_wrapped: bool,
+    next_seq: u64,
+}
+
+impl Timers {
+    fn schedule(&mut self, at: Duration, id: u32, interval: Option<Duration>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.push(Reverse(Timer {
+            at,
+            seq,
+            id,
+            interval,
+        }));
+    }
+
+    fn new_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = match id.checked_add(1) {
+                Some(next) => next,
+                None => {
+                    self.ids_wrapped = true;
+                    1
+                }
+            };
+            if !self.ids_wrapped || !self.queue.iter().any(|timer| timer.0.id == id) {
+                return id;
+            }
+        }
+    }
+}
+
+impl Context {
+    /// Add the `setTimeout`, `setInterval`, `clearTimeout` and
+    /// `clearInterval` globals, with time kept by `clock`.
+    ///
+    /// Installing again only replaces the clock.
+    ///
+    /// ```
+    ///     use std::time::Duration;
+    ///     use duktape::{Context, ManualClock};
+    ///
+    ///     let mut ctx = Context::default();
+    ///     let clock = ManualClock::new();
+    ///     ctx.install_timers(clock.clone());
+    ///     ctx.eval::<()>("var fired = false; setTimeout(function () { fired = true }, 100)")
+    ///         .unwrap();
+    ///     ctx.pop().unwrap();
+    ///
+    ///     clock.advance(Duration::from_millis(99));
+    ///     ctx.run_until_idle().unwrap();
+    ///     assert!(!ctx.eval::<bool>("fired").unwrap());
+    ///     ctx.pop().unwrap();
+    ///
+    ///     clock.advance(Duration::from_millis(1));
+    ///     ctx.run_until_idle().unwrap();
+    ///     assert!(ctx.eval::<bool>("fired").unwrap());
+    /// ```
+    pub fn install_timers<C: Clock + 'static>(&mut self, clock: C) {
+        let mut timers = self.heap().timers.borrow_mut();
+        if let Some(timers) = timers.as_mut() {
+            // keep pending timers, only the clock changes
+            timers.clock = Box::new(clock);
+            return;
+        }
+        *timers = Some(Timers {
+            clock: Box::new(clock),
+            queue: BinaryHeap::new(),
+            next_id: 1,
+            ids_wrapped: false,
+            next_seq: 0,
+        });
+        drop(timers);
+
+        self.push_object();
+        self.put_stashed(TABLE_PROP);
+
+        self.push_throwing_closure(|ctx, args| add_timer(ctx, args, false));
+        self.put_global_string("setTimeout");
+        self.push_throwing_closure(|ctx, args| add_timer(ctx, args, true));
+        self.put_global_string("setInterval");
+        for name in ["clearTimeout", "clearInterval"] {
+            self.push_throwing_closure(clear_timer);
+            self.put_global_string(name);
+        }
+    }
+
+    /// Run promise jobs and the timers which are due, without waiting.
+    ///
+    /// Timers added by callbacks run too if they are already due, an
+    /// error thrown by a callback is returned and leaves the remaining
+    /// timers pending.
+    pub fn run_until_idle(&mut self) -> Result<(), Error> {
+        self.run_jobs()?;
+        let now = match self.heap().timers.borrow().as_ref() {
+            Some(timers) => timers.clock.now(),
+            None => return Ok(()),
+        };
+        while let Some(timer) = self.next_due(now) {
+            fire(self, timer)?;
+            self.run_jobs()?;
+        }
+        Ok(())
+    }
+
+    /// Run until no timers are left, sleeping on the clock in between.
+    ///
+    /// Never returns while an interval is active.
+    pub fn run_event_loop(&mut self) -> Result<(), Error> {
+        loop {
+            self.run_until_idle()?;
+            let timers = self.heap().timers.borrow();
+            let timers = match timers.as_ref() {
+                Some(timers) => timers,
+                None => return Ok(()),
+            };
+            match timers.queue.peek() {
+                Some(Reverse(timer)) => timers.clock.sleep_until(timer.at),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Number of timers waiting to fire.
+    pub(crate) fn pending_timers(&self) -> usize {
+        let timers = self.heap().timers.borrow();
+        timers.as_ref().map_or(0, |timers| timers.queue.len())
+    }
+
+    fn next_due(&mut self, now: Duration) -> Option<Timer> {
+        let mut timers = self.heap().timers.borrow_mut();
+        let timers = timers.as_mut()?;
+        match timers.queue.peek() {
+            Some(Reverse(timer)) if timer.at <= now => timers.queue.pop().map(|timer| timer.0),
+            _ => None,
+        }
+    }
+}
+
+fn timer_table(ctx: &mut Context) -> Result<JsObject, Error> {
+    ctx.get_stashed(TABLE_PROP)
+}
+
+// The table holds `[callback, ...args]` for every pending timer.
+fn fire(ctx: &mut Context, timer: Timer) -> Result<(), Error> {
+    let table = timer_table(ctx)?;
+    let entry: JsObject = table.get_index(ctx, timer.id)?;
+    match timer.interval {
+        Some(interval) => {
+            let mut timers = ctx.heap().timers.borrow_mut();
+            let timers = timers.as_mut().unwrap();
+            // ticks missed while the host didn't run timers, or the clock
+            // jumped, are skipped rather than all fired at once
+            let now = timers.clock.now();
+            let behind = now.saturating_sub(timer.at).as_nanos() % interval.as_nanos();
+            let at = now + interval - Duration::from_nanos(behind as u64);
+            timers.schedule(at, timer.id, Some(interval));
+        }
+        None => {
+            table.delete_index(ctx, timer.id)?;
+        }
+    }
+
+    let callback: JsFunction = entry.get_index(ctx, 0)?;
+    let len: u32 = entry.get(ctx, "length")?;
+    let args = (1..len)
+        .map(|i| entry.get_index(ctx, i))
+        .collect::<Result<Vec<JsRef>, _>>()?;
+    callback.call(ctx, args.iter().collect::<Vec<_>>())
+}
+
+fn add_timer(ctx: &mut Context, args: Args, repeat: bool) -> Result<u32, Throw> {
+    if args.is_empty() || unsafe { duktape_sys::duk_is_function(ctx.inner, 0) } == 0 {
+        return Err(Throw::type_error("callback is not a function"));
+    }
+    let callback: JsFunction = args.get(ctx, 0)?;
+    // like browsers, `ToNumber` the delay and treat NaN as 0
+    let delay = match args.len() {
+        0 | 1 => 0.0,
+        _ => {
+            ctx.dup(1);
+            ctx.safe_call(1, |ctx| unsafe {
+                duktape_sys::duk_to_number(ctx.inner, -1)
+            })?
+        }
+    };
+    let delay = match delay {
+        ms if ms > 0.0 => Duration::from_millis(ms.min(i32::MAX as f64) as u64),
+        _ => Duration::ZERO,
+    };
+
+    unsafe { duktape_sys::duk_push_array(ctx.inner) };
+    let entry: JsObject = ctx.pop_value()?;
+    entry.set_index(ctx, 0, &callback)?;
+    for i in 2..args.len() {
+        let arg: JsRef = args.get(ctx, i)?;
+        entry.set_index(ctx, i as u32 - 1, &arg)?;
+    }
+
+    let id = {
+        let mut timers = ctx.heap().timers.borrow_mut();
+        let timers = timers.as_mut().unwrap();
+        let id = timers.new_id();
+        let interval = repeat.then(|| delay.max(MIN_INTERVAL));
+        let at = timers.clock.now() + delay;
+        timers.schedule(at, id, interval);
+        id
+    };
+
+    let table = timer_table(ctx)?;
+    table.set_index(ctx, id, &entry)?;
+    Ok(id)
+}
+
+fn clear_timer(ctx: &mut Context, args: Args) -> Result<(), Throw> {
+    // ids are never 0, anything which isn't an id is ignored
+    if args.is_empty() || unsafe { duktape_sys::duk_is_number(ctx.inner, 0) } == 0 {
+        return Ok(());
+    }
+    let id = unsafe { duktape_sys::duk_get_number(ctx.inner, 0) };
+    if !(1.0..=u32::MAX as f64).contains(&id) || id.fract() != 0.0 {
+        return Ok(());
+    }
+    let id = id as u32;
+    if let Some(timers) = ctx.heap().timers.borrow_mut().as_mut() {
+        timers.queue.retain(|timer| timer.0.id != id);
+    }
+    let table = timer_table(ctx)?;
+    table.delete_index(ctx, id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::eval;
+
+    fn context() -> (Context, ManualClock) {
+        let mut ctx = Context::default();
+        let clock = ManualClock::new();
+        ctx.install_timers(clock.clone());
+        eval::<()>(&mut ctx, "var log = []");
+        (ctx, clock)
+    }
+
+    #[test]
+    fn timeouts_in_order() {
+        let (mut ctx, clock) = context();
+        eval::<()>(
+            &mut ctx,
+            "function add(s) { log.push(s) }
+            setTimeout(add, 100, 'a');
+            setTimeout(add, 50, 'b');
+            setTimeout(function () {
+                add('c');
+                setTimeout(add, 0, 'd');
+            }, 50);
+            clearTimeout(setTimeout(add, 10, 'cleared'))",
+        );
+        clock.advance(Duration::from_millis(60));
+        ctx.run_until_idle().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "b,c,d");
+        clock.advance(Duration::from_millis(40));
+        ctx.run_until_idle().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "b,c,d,a");
+        assert_eq!(ctx.stack_len(), 0);
+    }
+
+    #[test]
+    fn intervals() {
+        let (mut ctx, clock) = context();
+        eval::<()>(
+            &mut ctx,
+            "var id = setInterval(function () {
+                log.push(log.length);
+                if (log.length === 3) clearInterval(id);
+            }, 100)",
+        );
+        // ticks missed by a jump of the clock are skipped
+        clock.advance(Duration::from_millis(250));
+        ctx.run_until_idle().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "0");
+
+        ctx.run_event_loop().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "0,1,2");
+        assert_eq!(clock.now(), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn wrapped_ids_are_unique() {
+        let (mut ctx, _clock) = context();
+        let first: u32 = eval(&mut ctx, "setInterval(function () {}, 100)");
+        assert_eq!(first, 1);
+        if let Some(timers) = ctx.heap().timers.borrow_mut().as_mut() {
+            timers.next_id = u32::MAX;
+        }
+        let ids: String = eval(
+            &mut ctx,
+            "[setTimeout(function () {}), setTimeout(function () {})].join()",
+        );
+        assert_eq!(ids, format!("{},2", u32::MAX));
+    }
+
+    #[test]
+    fn promise_jobs_run_between_timers() {
+        let (mut ctx, _clock) = context();
+        ctx.install_promise().unwrap();
+        eval::<()>(
+            &mut ctx,
+            "setTimeout(function () {
+                Promise.resolve().then(function () { log.push('job') });
+                log.push('first');
+            });
+            setTimeout(function () { log.push('second') })",
+        );
+        ctx.run_event_loop().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "first,job,second");
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let (mut ctx, _clock) = context();
+        eval::<()>(
+            &mut ctx,
+            "setTimeout(function () { throw new Error('boom') });
+            setTimeout(function () { log.push('later') })",
+        );
+        match ctx.run_event_loop() {
+            Err(Error::Js(e)) => assert_eq!(e.message, "boom"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        ctx.run_event_loop().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "later");
+    }
+
+    #[test]
+    fn arguments_are_checked() {
+        let (mut ctx, clock) = context();
+        eval::<()>(
+            &mut ctx,
+            "setTimeout(function () { log.push('string') }, '100');
+            setTimeout(function () { log.push('nan') }, 'soon');
+            clearTimeout('nothing'); clearTimeout({}); clearTimeout()",
+        );
+        ctx.run_until_idle().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "nan");
+        clock.advance(Duration::from_millis(100));
+        ctx.run_until_idle().unwrap();
+        assert_eq!(eval::<String>(&mut ctx, "log.join()"), "nan,string");
+
+        for source in ["setTimeout('log.push(1)')", "setInterval()"] {
+            match ctx.eval::<()>(source) {
+                Err(Error::Js(e)) => assert_eq!(e.name.as_deref(), Some("TypeError")),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+        let caught: String = eval(
+            &mut ctx,
+            "try { setTimeout(null, 1); 'none' } catch (e) { e.name }",
+        );
+        assert_eq!(caught, "TypeError");
+    }
+}
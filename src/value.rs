@@ -1,5 +1,7 @@
+use crate::class;
 use crate::serialize;
 use crate::Context;
+use std::collections::BTreeMap;
 use std::rc::Rc;
 use thiserror::Error;
 
@@ -13,6 +15,11 @@ pub enum PeekError {
     Deserialize(#[from] serialize::Error),
     #[error("internal")]
     Internal,
+    #[error("expected {}", .0)]
+    Type(&'static str),
+    /// Reading the value threw, like a failed type check or a getter.
+    #[error("{}", .0)]
+    Thrown(#[source] Box<crate::Error>),
 }
 
 pub trait PushValue {
@@ -73,6 +80,13 @@ macro_rules! via_serde {
     };
 }
 
+impl PushValue for () {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        ctx.push_undefined();
+        ctx.stack_top()
+    }
+}
+
 impl PeekValue for () {
     fn peek_at(_ctx: &mut Context, _idx: i32) -> Result<Self, PeekError> {
         Ok(())
@@ -95,56 +109,17 @@ via_serde!(f32);
 via_serde!(f64);
 via_serde!(String);
 
-impl<T> PushValue for Rc<T> {
+/// The pushed object owns a reference to the value, which is released
+/// when the object is garbage collected.
+impl<T: 'static> PushValue for Rc<T> {
     fn push_to(self, ctx: &mut Context) -> u32 {
-        let idx = ctx.push_object();
-
-        let ptr = Rc::into_raw(self);
-
-        ctx.push_pointer(ptr as _);
-        ctx.put_prop_string(idx.try_into().unwrap(), "__rc");
-
-        ctx.push_string(std::any::type_name::<T>());
-        ctx.put_prop_string(idx.try_into().unwrap(), "__type");
-        idx
+        class::push_owned(ctx, Box::new(self))
     }
 }
 
-fn peek_rc<T>(ctx: &mut Context, idx: i32, copy: bool) -> Option<Rc<T>> {
-    ctx.get_object(idx);
-
-    if !ctx.get_prop(idx, "__type") {
-        return None;
-    }
-    let typ = ctx.get_string(-1);
-    ctx.pop_it();
-    if typ != std::any::type_name::<T>() {
-        return None;
-    }
-
-    if !ctx.get_prop(idx, "__rc") {
-        return None;
-    }
-    let ptr = ctx.get_pointer(-1);
-    ctx.pop_it();
-    if copy {
-        // increment because we just produced a new Rc and 1 rc is left in stack
-        unsafe { Rc::increment_strong_count(ptr) };
-    }
-    let rc = unsafe { Rc::from_raw(ptr as *const T) };
-    Some(rc)
-}
-
-impl<T> PeekValue for Rc<T> {
+impl<T: 'static> PeekValue for Rc<T> {
     fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
-        let this = peek_rc(ctx, idx, true).ok_or(PeekError::Internal)?;
-        Ok(this)
-    }
-
-    fn pop(ctx: &mut Context) -> Result<Self, PeekError> {
-        let val = peek_rc(ctx, -1, false).ok_or(PeekError::Internal)?;
-        ctx.pop_it();
-        Ok(val)
+        class::peek_owned(ctx, idx).ok_or(PeekError::Internal)
     }
 }
 
@@ -229,3 +204,239 @@ where
         SerdeValue(self).push_to(ctx)
     }
 }
+
+/// A snapshot of an arbitrary JS value.
+///
+/// Objects are copied with their own enumerable properties, references
+/// back to an object which is already being copied become `Undefined`.
+/// So do objects nested more than 64 levels deep and everything past the
+/// first 10000 array items and properties, scripts can't make a copy
+/// exhaust the host's stack or memory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsValue {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Buffer(Vec<u8>),
+    Array(Vec<JsValue>),
+    Object(BTreeMap<String, JsValue>),
+    Function,
+    Pointer(usize),
+}
+
+fn lossy_string(ctx: &mut Context, idx: i32) -> String {
+    let mut len = 0;
+    let ptr = unsafe { duktape_sys::duk_get_lstring(ctx.as_raw(), idx, &mut len) };
+    if ptr.is_null() {
+        return String::new();
+    }
+    let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    String::from_utf8_lossy(slice).into_owned()
+}
+
+const MAX_DEPTH: usize = 64;
+const MAX_ITEMS: usize = 10_000;
+
+// Objects being copied and how many more items may be copied.
+struct Walk {
+    visited: Vec<*mut std::ffi::c_void>,
+    budget: usize,
+}
+
+/// Copy the value at `idx`, getters and proxy traps may throw.
+pub(crate) fn peek_dynamic(ctx: &mut Context, idx: i32) -> JsValue {
+    let mut walk = Walk {
+        visited: Vec::new(),
+        budget: MAX_ITEMS,
+    };
+    copy_dynamic(ctx, idx, &mut walk)
+}
+
+fn copy_dynamic(ctx: &mut Context, idx: i32, walk: &mut Walk) -> JsValue {
+    use duktape_sys::*;
+
+    let raw = ctx.as_raw();
+    let idx = unsafe { duk_normalize_index(raw, idx) };
+    match unsafe { duk_get_type(raw, idx) } as u32 {
+        DUK_TYPE_NULL => JsValue::Null,
+        DUK_TYPE_BOOLEAN => JsValue::Bool(unsafe { duk_get_boolean(raw, idx) } != 0),
+        DUK_TYPE_NUMBER => JsValue::Number(unsafe { duk_get_number(raw, idx) }),
+        DUK_TYPE_STRING => JsValue::String(lossy_string(ctx, idx)),
+        DUK_TYPE_BUFFER => JsValue::Buffer(ctx.get_buffer_opt(idx).unwrap_or_default()),
+        DUK_TYPE_POINTER => JsValue::Pointer(unsafe { duk_get_pointer(raw, idx) } as usize),
+        DUK_TYPE_LIGHTFUNC => JsValue::Function,
+        DUK_TYPE_OBJECT => {
+            if unsafe { duk_is_function(raw, idx) } != 0 {
+                return JsValue::Function;
+            }
+            if unsafe { duk_is_buffer_data(raw, idx) } != 0 {
+                return JsValue::Buffer(ctx.get_buffer_opt(idx).unwrap_or_default());
+            }
+            let ptr = unsafe { duk_get_heapptr(raw, idx) };
+            if walk.visited.contains(&ptr) || walk.visited.len() == MAX_DEPTH {
+                return JsValue::Undefined;
+            }
+            walk.visited.push(ptr);
+            // the enumerator, key and value
+            unsafe { duk_require_stack(raw, 3) };
+            let value = if ctx.is_array(idx) {
+                let len = ctx.get_length(idx).min(walk.budget);
+                walk.budget -= len;
+                let mut items = Vec::with_capacity(len);
+                for i in 0..len {
+                    ctx.get_prop_index(i as u32, idx);
+                    items.push(copy_dynamic(ctx, -1, walk));
+                    ctx.pop_it();
+                }
+                JsValue::Array(items)
+            } else {
+                let mut props = BTreeMap::new();
+                unsafe { duk_enum(raw, idx, DUK_ENUM_OWN_PROPERTIES_ONLY) };
+                while walk.budget > 0 && unsafe { duk_next(raw, -1, 1) } != 0 {
+                    walk.budget -= 1;
+                    let key = lossy_string(ctx, -2);
+                    props.insert(key, copy_dynamic(ctx, -1, walk));
+                    ctx.pop_n(2);
+                }
+                ctx.pop_it();
+                JsValue::Object(props)
+            };
+            walk.visited.pop();
+            value
+        }
+        _ => JsValue::Undefined,
+    }
+}
+
+impl PeekValue for JsValue {
+    fn peek_at(ctx: &mut Context, idx: i32) -> Result<Self, PeekError> {
+        if unsafe { duktape_sys::duk_get_type(ctx.as_raw(), idx) } as u32
+            != duktape_sys::DUK_TYPE_OBJECT
+        {
+            return Ok(peek_dynamic(ctx, idx));
+        }
+        // getters and proxy traps may throw, walk objects in a protected call
+        ctx.dup(idx);
+        ctx.safe_call(1, |ctx| peek_dynamic(ctx, -1))
+            .map_err(|err| PeekError::Thrown(Box::new(err)))
+    }
+}
+
+impl PushValue for JsValue {
+    fn push_to(self, ctx: &mut Context) -> u32 {
+        match self {
+            JsValue::Undefined | JsValue::Function => ctx.push_undefined(),
+            JsValue::Null => ctx.push_null(),
+            JsValue::Bool(value) => ctx.push_bool(value),
+            JsValue::Number(value) => ctx.push_double(value),
+            JsValue::String(value) => ctx.push_string(&value),
+            JsValue::Buffer(value) => ctx.push_fixed_buffer(&value),
+            JsValue::Pointer(value) => ctx.push_pointer(value as *const _),
+            JsValue::Array(items) => {
+                let idx = ctx.push_array();
+                for (i, item) in items.into_iter().enumerate() {
+                    item.push_to(ctx);
+                    ctx.put_prop_index(idx as i32, i as u32);
+                }
+            }
+            JsValue::Object(props) => {
+                let idx = ctx.push_object();
+                for (key, value) in props {
+                    value.push_to(ctx);
+                    ctx.put_prop_string(idx as i32, &key);
+                }
+            }
+        }
+        ctx.stack_top()
+    }
+}
+
+#[test]
+fn test_js_value() {
+    let mut ctx = Context::default();
+    let value: JsValue = ctx
+        .eval("var o = {a: 1, b: [true, null, 'x'], f: function() {}}; o.self = o; o")
+        .unwrap();
+    let mut props = BTreeMap::new();
+    props.insert("a".to_string(), JsValue::Number(1.0));
+    props.insert(
+        "b".to_string(),
+        JsValue::Array(vec![
+            JsValue::Bool(true),
+            JsValue::Null,
+            JsValue::String("x".to_string()),
+        ]),
+    );
+    props.insert("f".to_string(), JsValue::Function);
+    props.insert("self".to_string(), JsValue::Undefined);
+    assert_eq!(value, JsValue::Object(props));
+    ctx.pop_it();
+
+    ctx.push(value.clone());
+    let same: JsValue = ctx.pop_value().unwrap();
+    let JsValue::Object(props) = same else {
+        panic!("expected an object")
+    };
+    assert_eq!(
+        props["b"],
+        JsValue::Array(vec![
+            JsValue::Bool(true),
+            JsValue::Null,
+            JsValue::String("x".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn test_js_value_throwing_getter() {
+    let mut ctx = Context::default();
+    ctx.eval::<()>("var o = { get x() { throw new Error('nope') } }")
+        .unwrap();
+    ctx.get_global_str("o");
+    match ctx.pop_value::<JsValue>() {
+        Err(PeekError::Thrown(err)) => assert_eq!(err.to_string(), "Error: nope"),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(!ctx.is_poisoned());
+    assert_eq!(ctx.eval::<u32>("1 + 2").unwrap(), 3);
+    ctx.pop_it();
+
+    // thrown values are copied too, without reporting what that throws
+    match ctx.eval::<()>("var e = { get x() { throw e } }; throw e") {
+        Err(crate::Error::Js(err)) => assert_eq!(err.value, JsValue::Undefined),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn test_js_value_limits() {
+    let mut ctx = Context::default();
+    match ctx.eval::<()>("var o = {}; for (var i = 0; i < 1e5; i++) o = { a: o }; throw o") {
+        Err(crate::Error::Js(err)) => {
+            let mut value = &err.value;
+            let mut depth = 0;
+            while let JsValue::Object(props) = value {
+                value = &props["a"];
+                depth += 1;
+            }
+            assert_eq!((depth, value), (64, &JsValue::Undefined));
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    ctx.pop_it();
+
+    // an object, converting the array to a string for the message would
+    // join all of its items
+    match ctx.eval::<()>("var a = []; a.length = 4e9; throw { a: a, b: a }") {
+        Err(crate::Error::Js(err)) => match err.value {
+            JsValue::Object(props) => {
+                assert!(matches!(&props["a"], JsValue::Array(a) if a.len() == 9_999));
+                assert_eq!(props.get("b"), None);
+            }
+            value => panic!("unexpected value {:?}", value),
+        },
+        res => panic!("unexpected result {:?}", res),
+    }
+}